@@ -17,6 +17,7 @@
     src = ./.;
     buildFeatures = [
       "smartcard"
+      "age"
     ];
     buildInputs = [
       nettle
@@ -34,9 +35,17 @@
         path = lib.makeBinPath [
           pkgs.systemd
           pkgs.e2fsprogs
+          pkgs.xfsprogs
           pkgs.cryptsetup
+          pkgs.mdadm
+          pkgs.lvm2
           pkgs.util-linux
+          pkgs.gptfdisk
           pkgs.bcachefs-tools
+          pkgs.btrfs-progs
+          pkgs.zfs
+          pkgs.tpm2-tools
+          pkgs.clevis
         ];
       in
       ''
@@ -1,24 +1,49 @@
 use {
     aargvark::{
-        traits_impls::AargvarkJson,
+        base::{
+            R,
+            VarkState,
+        },
+        help::{
+            HelpPattern,
+            HelpState,
+        },
+        traits_impls::{
+            AargvarkJson,
+            AargvarkTrait,
+        },
         vark,
         Aargvark,
     },
-    blockdev::lsblk,
+    blockdev::{
+        find_data_disk,
+        lsblk,
+        volume_disks,
+        LsblkDevice,
+    },
     loga::{
         ea,
         fatal,
+        DebugDisplay,
         Log,
         ResultContext,
     },
-    path_absolutize::Absolutize,
     std::{
-        fs::create_dir_all,
+        collections::HashSet,
+        fs::{
+            create_dir_all,
+            OpenOptions,
+        },
+        io::Write,
+        os::unix::fs::OpenOptionsExt,
         path::PathBuf,
     },
+    plan::Plan,
+    util::volume_mount_path,
     volumesetup::config::{
         self,
         Config,
+        Volume,
     },
 };
 
@@ -27,14 +52,227 @@ mod volumesetuplib;
 use volumesetuplib::*;
 
 #[derive(Aargvark)]
-struct Args {
+enum PlanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Aargvark)]
+struct ConfigArgs {
+    config: AargvarkJson<Config>,
+}
+
+#[derive(Aargvark)]
+struct RekeyArgs {
+    config: AargvarkJson<Config>,
+    /// The new key source, in the same format as the volume `encryption` config.
+    new_encryption: AargvarkJson<config::EncryptionMode>,
+    /// Add a keyslot for the new key instead of replacing the current key.
+    add: Option<()>,
+}
+
+#[derive(Aargvark)]
+enum SplitKeyRecipient {
+    /// An OpenPGP public key (cert) file, for the administrator's smartcard.
+    Openpgp(PathBuf),
+}
+
+#[derive(Aargvark)]
+struct SplitKeyArgs {
+    /// How many shares are required to unlock.
+    threshold: usize,
+    /// Directory to write the encrypted shares to.
+    out_dir: PathBuf,
+    /// The administrators to create shares for, one share each.
+    recipients: Vec<SplitKeyRecipient>,
+}
+
+#[derive(Aargvark)]
+struct SetupArgs {
     config: AargvarkJson<Config>,
     validate: Option<()>,
     debug: Option<()>,
+    /// Print the actions that would be taken instead of doing anything.
+    plan: Option<PlanFormat>,
 }
 
-fn main1() -> Result<(), loga::Error> {
-    let args = vark::<Args>();
+#[derive(Aargvark)]
+enum Command {
+    /// Set up the configured volumes. This is the default, `setup` can be omitted.
+    Setup(SetupArgs),
+    /// Print the state of the configured volumes as JSON.
+    Status(ConfigArgs),
+    /// Unmount the configured volumes and close their LUKS devices.
+    Lock(ConfigArgs),
+    /// Unlock encrypted volumes with the configured key and replace it with (or add)
+    /// a new key.
+    Rekey(RekeyArgs),
+    /// Generate a new random key and split it into shares for `threshold_key`
+    /// encryption, each encrypted to a different administrator.
+    SplitKey(SplitKeyArgs),
+}
+
+struct Args(Command);
+
+impl AargvarkTrait for Args {
+    fn vark(state: &mut VarkState) -> R<Self> {
+        let start = state.position();
+        match Command::vark(state) {
+            R::Ok(command) => return R::Ok(Args(command)),
+            R::Help(help) => return R::Help(help),
+            R::Err | R::EOF => {
+                // Not a subcommand (or a subcommand with bad arguments, but then the
+                // subcommand name will be the config path and fail too), so it's the default
+                // `setup` with the config as the first argument
+                state.rewind(start);
+                match SetupArgs::vark(state) {
+                    R::Ok(args) => return R::Ok(Args(Command::Setup(args))),
+                    R::Help(help) => return R::Help(help),
+                    R::Err => return R::Err,
+                    R::EOF => return R::EOF,
+                }
+            },
+        }
+    }
+
+    fn build_help_pattern(state: &mut HelpState) -> HelpPattern {
+        return Command::build_help_pattern(state);
+    }
+}
+
+fn setup_volume(log: &Log, plan: &Plan, volume: &Volume, claimed: &HashSet<PathBuf>) -> Result<(), loga::Error> {
+    if volume.recovery.is_some() &&
+        key::key_sources(volume.encryption.as_ref().unwrap_or(&config::EncryptionMode::None {}))?.is_empty() {
+        return Err(loga::err("The `recovery` option can only be used with encrypted volumes"));
+    }
+    if volume.luks_header_backup.is_some() {
+        match volume.fs.as_ref().unwrap_or(&config::FilesystemMode::Bcachefs {}) {
+            config::FilesystemMode::Ext4 {} | config::FilesystemMode::Xfs {} | config::FilesystemMode::Lvm(_) => { },
+            _ => {
+                return Err(
+                    loga::err("The `luks_header_backup` option can only be used with `ext4`, `xfs` and `lvm` filesystems"),
+                );
+            },
+        }
+    }
+    if volume.luks_format.is_some() {
+        match volume.fs.as_ref().unwrap_or(&config::FilesystemMode::Bcachefs {}) {
+            config::FilesystemMode::Ext4 {} | config::FilesystemMode::Xfs {} | config::FilesystemMode::Lvm(_) => { },
+            _ => {
+                return Err(
+                    loga::err("The `luks_format` option can only be used with `ext4`, `xfs` and `lvm` filesystems"),
+                );
+            },
+        }
+    }
+    if volume.luks_detached_header.is_some() {
+        match volume.fs.as_ref().unwrap_or(&config::FilesystemMode::Bcachefs {}) {
+            config::FilesystemMode::Ext4 {} | config::FilesystemMode::Xfs {} => { },
+            _ => {
+                return Err(loga::err("The `luks_detached_header` option can only be used with `ext4` and `xfs` filesystems"));
+            },
+        }
+        if key::key_sources(volume.encryption.as_ref().unwrap_or(&config::EncryptionMode::None {}))?.is_empty() {
+            return Err(loga::err("The `luks_detached_header` option can only be used with encrypted volumes"));
+        }
+        if volume.raid.is_some() || volume.partition.is_some() {
+            return Err(loga::err("The `luks_detached_header` option can't be used with `raid` or `partition`"));
+        }
+    }
+    let lsblk_unclaimed = || -> Result<Vec<LsblkDevice>, loga::Error> {
+        return Ok(lsblk()?.into_iter().filter(|b| !claimed.contains(&b.path)).collect());
+    };
+    let fs = volume.fs.as_ref().unwrap_or(&config::FilesystemMode::Bcachefs {});
+    match fs {
+        config::FilesystemMode::Ext4 {} | config::FilesystemMode::Xfs {} => { },
+        config::FilesystemMode::Lvm(_) => {
+            // Each logical volume has its own mountpoint
+            if volume.mountpoint.is_some() || volume.ensure_dirs.is_some() || volume.raid.is_some() {
+                return Err(
+                    loga::err("The `mountpoint`, `ensure_dirs` and `raid` options can't be used with `lvm`, configure these per logical volume instead"),
+                );
+            }
+            if volume.partition.is_some() {
+                return Err(
+                    loga::err("The `partition` option can only be used with `ext4` and `xfs` filesystems"),
+                );
+            }
+        },
+        _ => {
+            if volume.raid.is_some() {
+                return Err(loga::err("The `raid` option can only be used with `ext4` and `xfs` filesystems"));
+            }
+            if volume.partition.is_some() {
+                return Err(loga::err("The `partition` option can only be used with `ext4` and `xfs` filesystems"));
+            }
+        },
+    }
+    if volume.raid.is_some() && volume.partition.is_some() {
+        return Err(loga::err("The `raid` and `partition` options can't be used together"));
+    }
+
+    // Set up a volume with a single mountpoint
+    let setup_mounted =
+        |setup: &dyn Fn(Vec<LsblkDevice>, &PathBuf) -> Result<(), loga::Error>| -> Result<(), loga::Error> {
+            let mount_path = volume_mount_path(volume)?;
+
+            // Assemble the array first so the volume on it shows up (when planning, this is
+            // done when the members are found)
+            if volume.raid.is_some() && !plan.planning() {
+                raid::assemble(
+                    log,
+                    plan,
+                    &raid::array(volume.uuid.as_ref().map(|x| x.as_str()).unwrap_or(config::OUTER_UUID))?,
+                )?;
+            }
+            let blocks = lsblk_unclaimed()?;
+            for block in &blocks {
+                for mount in &block.mountpoints {
+                    let Some(mp) = mount else {
+                        continue;
+                    };
+                    if mp.as_str() == mount_path.to_string_lossy().as_ref() {
+                        log.log(loga::INFO, "Already mounted, doing nothing.");
+                        return Ok(());
+                    }
+                }
+            }
+            setup(blocks, &mount_path)?;
+
+            // Ensure subdirectories in mountpoint
+            for path in volume.ensure_dirs.iter().flatten() {
+                if plan.planned(format!("Create directory {}", mount_path.join(&path).dbg_str()), None) {
+                    continue;
+                }
+                create_dir_all(
+                    &mount_path.join(&path),
+                ).stack_context_with(log, "Failed to create mount point subidr", ea!(subdir = path.to_string_lossy()))?;
+            }
+            return Ok(());
+        };
+    match fs {
+        config::FilesystemMode::Ext4 {} => setup_mounted(
+            &|blocks, mount_path| fs_ext4::main(log, plan, blocks, volume, mount_path),
+        )?,
+        config::FilesystemMode::Xfs {} => setup_mounted(
+            &|blocks, mount_path| fs_xfs::main(log, plan, blocks, volume, mount_path),
+        )?,
+        config::FilesystemMode::Bcachefs {} => setup_mounted(
+            &|blocks, mount_path| fs_bcachefs::main(log, plan, blocks, volume, mount_path),
+        )?,
+        config::FilesystemMode::Btrfs(fs_args) => setup_mounted(
+            &|blocks, mount_path| fs_btrfs::main(log, plan, blocks, volume, fs_args, mount_path),
+        )?,
+        config::FilesystemMode::Zfs(fs_args) => setup_mounted(
+            &|blocks, mount_path| fs_zfs::main(log, plan, blocks, volume, fs_args, mount_path),
+        )?,
+        config::FilesystemMode::Lvm(fs_args) => fs_lvm::main(log, plan, lsblk_unclaimed()?, volume, fs_args)?,
+    }
+    return Ok(());
+}
+
+fn setup(args: SetupArgs) -> Result<(), loga::Error> {
+    let volumes = args.config.value.into_volumes()?;
     if args.validate.is_some() {
         return Ok(());
     }
@@ -43,37 +281,117 @@ fn main1() -> Result<(), loga::Error> {
     } else {
         loga::INFO
     });
-    let config = args.config.value;
-    let mount_path =
-        config
-            .mountpoint
-            .clone()
-            .unwrap_or_else(|| PathBuf::from("/mnt/persistent"))
-            .absolutize()
-            .context("Couldn't make mountpoint absolute")?
-            .into_owned();
+
+    // Data disks of volumes with detached LUKS headers have no signatures, so they'd
+    // look unused to earlier volumes
     let blocks = lsblk()?;
-    for block in &blocks {
-        for mount in &block.mountpoints {
-            let Some(mp) = mount else {
-                continue;
-            };
-            if mp.as_str() == mount_path.to_string_lossy().as_ref() {
-                log.log(loga::INFO, "Already mounted, doing nothing.");
-                return Ok(());
+    let mut data_disks = vec![];
+    for volume in &volumes {
+        data_disks.push(match &volume.luks_detached_header {
+            Some(detached) => find_data_disk(&blocks, &detached.data_disk)?,
+            None => None,
+        });
+    }
+
+    // Set up volumes, excluding disks used by earlier volumes from later volumes
+    let mut claimed = HashSet::new();
+    let mut plans = vec![];
+    for (i, volume) in volumes.iter().enumerate() {
+        let log = log.fork(ea!(volume = i));
+        let plan = Plan::new(args.plan.is_some());
+        let mut exclude = claimed.clone();
+        for (j, data_disk) in data_disks.iter().enumerate() {
+            if j != i {
+                exclude.extend(data_disk.clone());
             }
         }
+        setup_volume(&log, &plan, volume, &exclude)?;
+        claimed.extend(volume_disks(volume)?);
+
+        // When planning nothing was actually set up, so also claim the disks the volume
+        // would use
+        claimed.extend(plan.claimed());
+        if plan.planning() {
+            plans.push(plan::VolumePlan {
+                volume: i,
+                actions: plan.into_actions(),
+            });
+        }
     }
-    match config.fs.as_ref().unwrap_or(&config::FilesystemMode::Bcachefs {}) {
-        config::FilesystemMode::Ext4 {} => fs_ext4::main(&log, blocks, &config, &mount_path)?,
-        config::FilesystemMode::Bcachefs {} => fs_bcachefs::main(&log, blocks, &config, &mount_path)?,
+    match args.plan {
+        None => { },
+        Some(PlanFormat::Text) => {
+            print!("{}", plan::to_text(&plans));
+        },
+        Some(PlanFormat::Json) => {
+            println!("{}", serde_json::to_string_pretty(&plans).unwrap());
+        },
     }
+    return Ok(());
+}
+
+fn main1() -> Result<(), loga::Error> {
+    match vark::<Args>().0 {
+        Command::Setup(args) => {
+            setup(args)?;
+        },
+        Command::Status(args) => {
+            let mut out = vec![];
+            for (i, volume) in args.config.value.into_volumes()?.iter().enumerate() {
+                out.push(status::volume_status(i, volume)?);
+            }
+            println!("{}", serde_json::to_string_pretty(&out).unwrap());
+        },
+        Command::Lock(args) => {
+            let log = Log::new_root(loga::INFO);
+            let plan = Plan::new(false);
+
+            // Later volumes may be mounted inside earlier volumes
+            for (i, volume) in args.config.value.into_volumes()?.iter().enumerate().rev() {
+                lock::lock_volume(&log.fork(ea!(volume = i)), &plan, volume)?;
+            }
+        },
+        Command::Rekey(args) => {
+            let log = Log::new_root(loga::INFO);
+            let plan = Plan::new(false);
+            let Some(new_key) = key::get_key(&log, &plan, &args.new_encryption.value, true)? else {
+                return Err(loga::err("The new key source can't be `none`"));
+            };
+            for (i, volume) in args.config.value.into_volumes()?.iter().enumerate() {
+                rekey::rekey_volume(&log.fork(ea!(volume = i)), &plan, volume, &new_key, args.add.is_some())?;
+            }
+            log.log(loga::INFO, "Done, update the config to use the new key source");
+        },
+        Command::SplitKey(args) => {
+            let log = Log::new_root(loga::INFO);
+            let shares = shamir::split(&key::new_random_key(), args.threshold, args.recipients.len())?;
+            create_dir_all(
+                &args.out_dir,
+            ).context_with("Error creating share output directory", ea!(path = args.out_dir.dbg_str()))?;
 
-    // Ensure subdirectories in mountpoint
-    for path in config.ensure_dirs.unwrap_or_default() {
-        create_dir_all(
-            &mount_path.join(&path),
-        ).stack_context_with(&log, "Failed to create mount point subidr", ea!(subdir = path.to_string_lossy()))?;
+            // Shares are only ever written encrypted
+            for (i, (share, recipient)) in shares.into_iter().zip(args.recipients).enumerate() {
+                let recipient = match recipient {
+                    SplitKeyRecipient::Openpgp(path) => config::EscrowRecipient::Openpgp(path),
+                };
+                let encrypted =
+                    recovery::encrypt(
+                        &[recipient],
+                        format!("{}\n", share).as_bytes(),
+                    ).context_with("Error encrypting share", ea!(share = i + 1))?;
+                let path = args.out_dir.join(format!("share-{}.asc", i + 1));
+                OpenOptions::new()
+                    .mode(0o600)
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .context_with("Error creating share file", ea!(path = path.dbg_str()))?
+                    .write_all(&encrypted)
+                    .context_with("Error writing share file", ea!(path = path.dbg_str()))?;
+                log.log(loga::INFO, format!("Wrote encrypted share [{}]", path.dbg_str()));
+            }
+            log.log(loga::INFO, "Done, give each administrator their share or copy the shares to the configured paths");
+        },
     }
     return Ok(());
 }
@@ -0,0 +1,193 @@
+use {
+    super::key::ask_password,
+    crate::{
+        config::AgeIdentityMode,
+        util::from_utf8,
+    },
+    age::{
+        armor::{
+            ArmoredReader,
+            ArmoredWriter,
+            Format,
+        },
+        secrecy::Secret,
+        Decryptor,
+        Encryptor,
+        Identity,
+        Recipient,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ResultContext,
+    },
+    std::{
+        fs::{
+            read,
+            File,
+        },
+        io::{
+            stdin,
+            BufReader,
+            Read,
+            Write,
+        },
+        path::Path,
+        str::FromStr,
+    },
+};
+
+/// Passphrases for encrypted SSH keys are requested with `systemd-ask-password`
+/// like other passwords.
+#[derive(Clone)]
+struct AskPasswordCallbacks;
+
+impl age::Callbacks for AskPasswordCallbacks {
+    fn display_message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn confirm(&self, _message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
+        None
+    }
+
+    fn request_public_string(&self, _description: &str) -> Option<String> {
+        None
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<Secret<String>> {
+        return ask_password(description).ok().map(Secret::new);
+    }
+}
+
+pub(crate) enum AgeIdentity {
+    Identities(Vec<Box<dyn Identity>>),
+    Passphrase(Secret<String>),
+}
+
+pub(crate) fn load_identity(mode: &AgeIdentityMode) -> Result<AgeIdentity, loga::Error> {
+    let data = match mode {
+        AgeIdentityMode::Passphrase => {
+            return Ok(AgeIdentity::Passphrase(Secret::new(ask_password("Enter the key file passphrase")?)));
+        },
+        AgeIdentityMode::File(path) => {
+            read(path).context_with("Error reading age identity", ea!(path = path.dbg_str()))?
+        },
+        AgeIdentityMode::Stdin => {
+            let mut data = vec![];
+            stdin().read_to_end(&mut data).context("Error reading age identity from stdin")?;
+            data
+        },
+    };
+    let text = from_utf8(data).context("Age identity isn't valid utf-8")?;
+    let mut identities: Vec<Box<dyn Identity>> = vec![];
+    if text.contains("-----BEGIN") {
+        let identity =
+            age::ssh::Identity::from_buffer(
+                BufReader::new(text.as_bytes()),
+                None,
+            ).context("Error parsing SSH private key")?;
+        if let age::ssh::Identity::Unsupported(_) = &identity {
+            return Err(loga::err("Unsupported SSH key type, only `ssh-ed25519` and `ssh-rsa` keys can be used"));
+        }
+        identities.push(Box::new(identity.with_callbacks(AskPasswordCallbacks)));
+    } else {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            identities.push(
+                Box::new(
+                    age::x25519::Identity::from_str(
+                        line,
+                    ).map_err(|e| loga::err_with("Error parsing age identity", ea!(err = e)))?,
+                ),
+            );
+        }
+    }
+    if identities.is_empty() {
+        return Err(loga::err("No identities found in age identity"));
+    }
+    return Ok(AgeIdentity::Identities(identities));
+}
+
+/// Decrypt an age encrypted (binary or ASCII armored) key file.
+pub(crate) fn decrypt(key_path: &Path, identity: &AgeIdentity) -> Result<String, loga::Error> {
+    let decryptor =
+        Decryptor::new(
+            ArmoredReader::new(
+                BufReader::new(
+                    File::open(
+                        key_path,
+                    ).context_with("Error opening encrypted key", ea!(path = key_path.dbg_str()))?,
+                ),
+            ),
+        ).map_err(
+            |e| loga::err(e.to_string()).context_with("Error reading age encrypted key", ea!(path = key_path.dbg_str())),
+        )?;
+    let mut reader = match (decryptor, identity) {
+        (Decryptor::Recipients(d), AgeIdentity::Identities(identities)) => {
+            d.decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))
+        },
+        (Decryptor::Passphrase(d), AgeIdentity::Passphrase(passphrase)) => {
+            d.decrypt(passphrase, None)
+        },
+        (Decryptor::Recipients(_), AgeIdentity::Passphrase(_)) => {
+            return Err(
+                loga::err_with(
+                    "Key file is encrypted to recipients but the identity mode is `passphrase`",
+                    ea!(path = key_path.dbg_str()),
+                ),
+            );
+        },
+        (Decryptor::Passphrase(_), AgeIdentity::Identities(_)) => {
+            return Err(
+                loga::err_with(
+                    "Key file is encrypted with a passphrase, use the `passphrase` identity mode",
+                    ea!(path = key_path.dbg_str()),
+                ),
+            );
+        },
+    }.map_err(|e| loga::err(e.to_string()).context_with("Decryption failed", ea!(path = key_path.dbg_str())))?;
+    let mut decrypted = vec![];
+    reader
+        .read_to_end(&mut decrypted)
+        .context_with("Error reading decrypted key", ea!(path = key_path.dbg_str()))?;
+    return Ok(from_utf8(decrypted).context("Key file contains invalid utf-8")?.trim().to_string());
+}
+
+/// Encrypt (ASCII armored) to age recipients (`age1...`) or SSH public keys.
+pub(crate) fn encrypt(recipients: &[&str], data: &[u8]) -> Result<Vec<u8>, loga::Error> {
+    let mut parsed: Vec<Box<dyn Recipient + Send>> = vec![];
+    for recipient in recipients {
+        if recipient.starts_with("age1") {
+            parsed.push(
+                Box::new(
+                    age::x25519::Recipient::from_str(
+                        recipient,
+                    ).map_err(|e| loga::err_with("Error parsing age recipient", ea!(err = e)))?,
+                ),
+            );
+        } else {
+            parsed.push(
+                Box::new(
+                    age::ssh::Recipient::from_str(
+                        recipient,
+                    ).map_err(|e| loga::err_with("Error parsing SSH recipient", ea!(err = e.dbg_str())))?,
+                ),
+            );
+        }
+    }
+    let encryptor = Encryptor::with_recipients(parsed).context("No age recipients")?;
+    let mut out = vec![];
+    let mut writer =
+        encryptor
+            .wrap_output(
+                ArmoredWriter::wrap_output(&mut out, Format::AsciiArmor).context("Error setting up age armor")?,
+            )
+            .context("Error setting up age encryption")?;
+    writer.write_all(data).context("Error writing age encrypted data")?;
+    writer.finish().and_then(|armor| armor.finish()).context("Error finishing age encryption")?;
+    return Ok(out);
+}
@@ -1,11 +1,47 @@
 use {
-    crate::util::SimpleCommandExt,
-    loga::ResultContext,
+    crate::{
+        config::{
+            DataDisk,
+            DiskMatch,
+            DiskSelection,
+            FilesystemMode,
+            OUTER_UUID,
+            RAID_UUID,
+            RemovablePolicy,
+            Volume,
+            WipePolicy,
+        },
+        partition::partition_path,
+        util::{
+            derive_uuid,
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    glob::Pattern,
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
     serde::Deserialize,
     std::{
         cmp::Reverse,
-        collections::HashSet,
-        path::PathBuf,
+        collections::{
+            HashMap,
+            HashSet,
+        },
+        fs::{
+            canonicalize,
+            read_dir,
+            File,
+        },
+        io::Read,
+        path::{
+            Path,
+            PathBuf,
+        },
         process::Command,
     },
 };
@@ -16,7 +52,7 @@ pub(crate) struct LsblkRoot {
     blockdevices: Vec<LsblkDevice>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct LsblkDevice {
     /// Path to the device node
@@ -33,10 +69,26 @@ pub(crate) struct LsblkDevice {
     pub(crate) type_: String,
     /// Filesystem UUID. Not always a standard uuid, can be 8 characters.
     pub(crate) uuid: Option<String>,
+    /// Filesystem type
+    pub(crate) fstype: Option<String>,
+    /// Filesystem label
+    pub(crate) label: Option<String>,
     #[serde(default)]
     pub(crate) children: Vec<LsblkDevice>,
     /// Rotational - true = hdd, missing = maybe raid, assume rotational
     pub(crate) rota: Option<bool>,
+    /// Device model
+    pub(crate) model: Option<String>,
+    /// Device serial
+    pub(crate) serial: Option<String>,
+    /// Device WWN
+    pub(crate) wwn: Option<String>,
+    /// Device transport (nvme, sata, usb, ...)
+    pub(crate) tran: Option<String>,
+    /// Partition table type
+    pub(crate) pttype: Option<String>,
+    /// Logical sector size in bytes
+    pub(crate) log_sec: Option<u64>,
 }
 
 pub(crate) fn lsblk() -> Result<Vec<LsblkDevice>, loga::Error> {
@@ -53,16 +105,255 @@ pub(crate) fn lsblk() -> Result<Vec<LsblkDevice>, loga::Error> {
     );
 }
 
-pub(crate) fn find_unused(blocks: Vec<LsblkDevice>) -> Result<Vec<LsblkDevice>, loga::Error> {
+/// Map of device paths to their names in `/dev/disk/by-id`.
+fn by_id_names() -> Result<HashMap<PathBuf, Vec<String>>, loga::Error> {
+    let mut out = HashMap::<PathBuf, Vec<String>>::new();
+    let by_id_dir = "/dev/disk/by-id";
+    if !PathBuf::from(by_id_dir).exists() {
+        return Ok(out);
+    }
+    for e in read_dir(by_id_dir).context_with("Error reading device ids", ea!(path = by_id_dir))? {
+        let e = e.context_with("Error reading device id entry", ea!(path = by_id_dir))?;
+        let Ok(dev_path) = canonicalize(e.path()) else {
+            continue;
+        };
+        out.entry(dev_path).or_default().push(e.file_name().to_string_lossy().to_string());
+    }
+    return Ok(out);
+}
+
+fn glob_matches(pattern: &str, value: Option<&String>) -> Result<bool, loga::Error> {
+    let pattern = Pattern::new(pattern).context_with("Invalid glob in disk selection", ea!(glob = pattern))?;
+    return Ok(value.map(|v| pattern.matches(v)).unwrap_or(false));
+}
+
+fn disk_matches(m: &DiskMatch, candidate: &LsblkDevice, by_id: &[String]) -> Result<bool, loga::Error> {
+    if let Some(pattern) = &m.path {
+        if !glob_matches(pattern, Some(&candidate.path.to_string_lossy().to_string()))? {
+            return Ok(false);
+        }
+    }
+    if let Some(pattern) = &m.by_id {
+        let mut any = false;
+        for name in by_id {
+            if glob_matches(pattern, Some(name))? {
+                any = true;
+                break;
+            }
+        }
+        if !any {
+            return Ok(false);
+        }
+    }
+    if let Some(pattern) = &m.model {
+        if !glob_matches(pattern, candidate.model.as_ref().map(|x| x.trim().to_string()).as_ref())? {
+            return Ok(false);
+        }
+    }
+    if let Some(pattern) = &m.serial {
+        if !glob_matches(pattern, candidate.serial.as_ref())? {
+            return Ok(false);
+        }
+    }
+    if let Some(pattern) = &m.wwn {
+        if !glob_matches(pattern, candidate.wwn.as_ref())? {
+            return Ok(false);
+        }
+    }
+    if let Some(transport) = &m.transport {
+        if candidate.tran.as_ref() != Some(transport) {
+            return Ok(false);
+        }
+    }
+    if let Some(rotational) = m.rotational {
+        if candidate.rota.unwrap_or(true) != rotational {
+            return Ok(false);
+        }
+    }
+    if let Some(min_size) = m.min_size {
+        if (candidate.size as u64) < min_size {
+            return Ok(false);
+        }
+    }
+    if let Some(max_size) = m.max_size {
+        if (candidate.size as u64) > max_size {
+            return Ok(false);
+        }
+    }
+    return Ok(true);
+}
+
+/// Physical disks allowed by the selection rules, whether in use or not.
+pub(crate) fn find_selectable(
+    blocks: Vec<LsblkDevice>,
+    selection: Option<&DiskSelection>,
+) -> Result<Vec<LsblkDevice>, loga::Error> {
+    let by_id = match selection {
+        Some(_) => by_id_names()?,
+        None => HashMap::new(),
+    };
     let mut out = vec![];
-    for candidate in blocks {
+    'next_candidate: for candidate in blocks {
         let subsystems = candidate.subsystems.split(":").collect::<HashSet<&str>>();
 
         // Only consider physical disks
-        if candidate.type_ != "disk" || subsystems.contains("usb") {
+        if candidate.type_ != "disk" {
+            continue;
+        }
+        let candidate_by_id =
+            canonicalize(&candidate.path)
+                .ok()
+                .and_then(|p| by_id.get(&p))
+                .map(|x| x.as_slice())
+                .unwrap_or_default();
+
+        // Skip usb disks unless allowed
+        if subsystems.contains("usb") {
+            match selection.and_then(|s| s.removable.as_ref()).unwrap_or(&RemovablePolicy::Deny) {
+                RemovablePolicy::Deny => {
+                    continue 'next_candidate;
+                },
+                RemovablePolicy::Allow => { },
+                RemovablePolicy::AllowMatching(allow) => {
+                    let mut any = false;
+                    for m in allow {
+                        if disk_matches(m, &candidate, candidate_by_id)? {
+                            any = true;
+                            break;
+                        }
+                    }
+                    if !any {
+                        continue 'next_candidate;
+                    }
+                },
+            }
+        }
+
+        // Apply configured selection rules
+        if let Some(selection) = selection {
+            if let Some(include) = &selection.include {
+                let mut any = false;
+                for m in include {
+                    if disk_matches(m, &candidate, candidate_by_id)? {
+                        any = true;
+                        break;
+                    }
+                }
+                if !any {
+                    continue 'next_candidate;
+                }
+            }
+            for m in selection.exclude.iter().flatten() {
+                if disk_matches(m, &candidate, candidate_by_id)? {
+                    continue 'next_candidate;
+                }
+            }
+        }
+
+        // Maybe keep as candidate
+        out.push(candidate);
+    }
+    return Ok(out);
+}
+
+/// The data disk of a volume with a detached LUKS header, if it's attached.
+pub(crate) fn find_data_disk(blocks: &Vec<LsblkDevice>, disk: &DataDisk) -> Result<Option<PathBuf>, loga::Error> {
+    match disk {
+        DataDisk::Serial(serial) => {
+            let mut found = None;
+            for candidate in blocks {
+                if candidate.type_ != "disk" || candidate.serial.as_ref().map(|x| x.trim()) != Some(serial.as_str()) {
+                    continue;
+                }
+                if found.is_some() {
+                    return Err(loga::err_with("Multiple disks found with the data disk serial", ea!(serial = serial)));
+                }
+                found = Some(candidate.path.clone());
+            }
+            return Ok(found);
+        },
+        DataDisk::ById(name) => {
+            let by_id_path = Path::new("/dev/disk/by-id").join(name);
+            if !by_id_path.exists() {
+                return Ok(None);
+            }
+            return Ok(
+                Some(
+                    canonicalize(&by_id_path).context_with("Error resolving data disk", ea!(path = by_id_path.dbg_str()))?,
+                ),
+            );
+        },
+    }
+}
+
+/// Low level probe for filesystem, RAID, LVM, LUKS and partition table signatures
+/// on the device.
+pub(crate) fn signatures(dev_path: &Path) -> Result<Vec<String>, loga::Error> {
+    let raw =
+        from_utf8(
+            Command::new("wipefs")
+                .arg("--noheadings")
+                .arg("--output=TYPE")
+                .arg(dev_path)
+                .simple()
+                .run_stdout()
+                .context_with("Error probing disk for existing signatures", ea!(disk = dev_path.dbg_str()))?,
+        ).context("Signature probe output isn't valid utf-8")?;
+    let mut out = vec![];
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.is_empty() || out.iter().any(|x| x == line) {
             continue;
         }
+        out.push(line.to_string());
+    }
+    return Ok(out);
+}
+
+/// Like `find_data_disk`, but it's an error if the disk isn't attached.
+pub(crate) fn require_data_disk(blocks: &Vec<LsblkDevice>, disk: &DataDisk) -> Result<PathBuf, loga::Error> {
+    if let Some(path) = find_data_disk(blocks, disk)? {
+        return Ok(path);
+    }
+    match disk {
+        DataDisk::Serial(serial) => {
+            return Err(loga::err_with("Couldn't find data disk with serial", ea!(serial = serial)));
+        },
+        DataDisk::ById(name) => {
+            return Err(loga::err_with("Couldn't find data disk in `/dev/disk/by-id`", ea!(name = name)));
+        },
+    }
+}
 
+/// No signatures and the start of the disk is zeroed. An encrypted disk without a
+/// header has no signatures, but looks like random data.
+pub(crate) fn is_blank(dev_path: &Path) -> Result<bool, loga::Error> {
+    if !signatures(dev_path)?.is_empty() {
+        return Ok(false);
+    }
+    let mut start = vec![];
+    File::open(dev_path)
+        .context_with("Error opening disk", ea!(disk = dev_path.dbg_str()))?
+        .take(1024 * 1024)
+        .read_to_end(&mut start)
+        .context_with("Error reading start of disk", ea!(disk = dev_path.dbg_str()))?;
+    return Ok(start.iter().all(|b| *b == 0));
+}
+
+/// Unused physical disks allowed by the selection rules, largest first. Disks
+/// containing existing data are skipped unless the selection allows wiping them.
+pub(crate) fn find_unused(
+    log: &Log,
+    blocks: Vec<LsblkDevice>,
+    selection: Option<&DiskSelection>,
+) -> Result<Vec<LsblkDevice>, loga::Error> {
+    let wipe = selection.and_then(|s| s.allow_wipe.as_ref()).unwrap_or(&WipePolicy::Deny);
+    let by_id = match wipe {
+        WipePolicy::AllowMatching(_) => by_id_names()?,
+        _ => HashMap::new(),
+    };
+    let mut out = vec![];
+    'next_candidate: for candidate in find_selectable(blocks, selection)? {
         // Skip in-use devices (recursive)
         fn in_use(candidate: &LsblkDevice) -> bool {
             if candidate.mountpoints.iter().filter(|p| p.is_some()).count() > 0 {
@@ -80,9 +371,151 @@ pub(crate) fn find_unused(blocks: Vec<LsblkDevice>) -> Result<Vec<LsblkDevice>,
             continue;
         }
 
+        // Skip disks with existing data unless wiping is allowed
+        let mut signatures = match signatures(&candidate.path) {
+            Ok(s) => s,
+            Err(e) => {
+                log.log_err(
+                    loga::WARN,
+                    e.context_with("Skipping disk that couldn't be probed", ea!(disk = candidate.path.dbg_str())),
+                );
+                continue 'next_candidate;
+            },
+        };
+        if signatures.is_empty() && !candidate.children.is_empty() {
+            signatures.push("partitions".to_string());
+        }
+        if !signatures.is_empty() {
+            match wipe {
+                WipePolicy::Deny => {
+                    log.log_with(
+                        loga::WARN,
+                        "Skipping unmounted disk with existing signatures, set `allow_wipe` to use it",
+                        ea!(disk = candidate.path.dbg_str(), signatures = signatures.join(", ")),
+                    );
+                    continue 'next_candidate;
+                },
+                WipePolicy::Allow => { },
+                WipePolicy::AllowMatching(allow) => {
+                    let candidate_by_id =
+                        canonicalize(&candidate.path)
+                            .ok()
+                            .and_then(|p| by_id.get(&p))
+                            .map(|x| x.as_slice())
+                            .unwrap_or_default();
+                    let mut any = false;
+                    for m in allow {
+                        if disk_matches(m, &candidate, candidate_by_id)? {
+                            any = true;
+                            break;
+                        }
+                    }
+                    if !any {
+                        log.log_with(
+                            loga::WARN,
+                            "Skipping unmounted disk with existing signatures, not allowed by `allow_wipe`",
+                            ea!(disk = candidate.path.dbg_str(), signatures = signatures.join(", ")),
+                        );
+                        continue 'next_candidate;
+                    }
+                },
+            }
+            log.log_with(
+                loga::WARN,
+                "Disk has existing signatures, they will be overwritten",
+                ea!(disk = candidate.path.dbg_str(), signatures = signatures.join(", ")),
+            );
+        }
+
         // Maybe keep as candidate
         out.push((candidate.size, candidate));
     }
     out.sort_by_cached_key(|v| Reverse(v.0));
     return Ok(out.into_iter().map(|x| x.1).collect());
 }
+
+/// Top level devices (disks) where the device itself or any descendant matches
+/// `f`.
+pub(crate) fn find_disks(blocks: &Vec<LsblkDevice>, f: impl Fn(&LsblkDevice) -> bool) -> HashSet<PathBuf> {
+    fn matches(candidate: &LsblkDevice, f: &impl Fn(&LsblkDevice) -> bool) -> bool {
+        if f(candidate) {
+            return true;
+        }
+        for child in &candidate.children {
+            if matches(child, f) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    let mut out = HashSet::new();
+    for candidate in blocks {
+        if matches(candidate, &f) {
+            out.insert(candidate.path.clone());
+        }
+    }
+    return out;
+}
+
+/// The disks backing the volume, to exclude from later volumes.
+pub(crate) fn volume_disks(volume: &Volume) -> Result<HashSet<PathBuf>, loga::Error> {
+    let blocks = lsblk()?;
+    match volume.fs.as_ref().unwrap_or(&FilesystemMode::Bcachefs {}) {
+        FilesystemMode::Ext4 {} |
+        FilesystemMode::Xfs {} |
+        FilesystemMode::Bcachefs {} |
+        FilesystemMode::Btrfs(_) => {
+            let uuid = volume.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
+
+            // The rest of the disk may be used by other volumes
+            if volume.partition.is_some() {
+                return Ok(canonicalize(partition_path(uuid)?).into_iter().collect());
+            }
+
+            // Also find array members in case the array isn't assembled
+            let raid_uuid = match &volume.raid {
+                Some(_) => Some(derive_uuid(uuid, RAID_UUID)?),
+                None => None,
+            };
+            let mut out =
+                find_disks(&blocks, |b| b.uuid.is_some() && (b.uuid.as_deref() == Some(uuid) || b.uuid == raid_uuid));
+
+            // The data disk has no header, so it's only known via the configuration
+            if let Some(detached) = &volume.luks_detached_header {
+                if detached.header.exists() {
+                    out.extend(find_data_disk(&blocks, &detached.data_disk)?);
+                    if let Ok(header_path) = canonicalize(&detached.header) {
+                        out.extend(find_disks(&blocks, |b| b.path == header_path));
+                    }
+                }
+            }
+            return Ok(out);
+        },
+        FilesystemMode::Zfs(fs_args) => {
+            let pool = fs_args.pool.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            return Ok(
+                find_disks(
+                    &blocks,
+                    |b| b.fstype.as_ref().map(|x| x.as_str()) == Some("zfs_member") &&
+                        b.label.as_ref().map(|x| x.as_str()) == Some(pool),
+                ),
+            );
+        },
+        FilesystemMode::Lvm(fs_args) => {
+            let vg = fs_args.volume_group.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            let pvs =
+                from_utf8(
+                    Command::new("pvs")
+                        .arg("--noheadings")
+                        .arg("--options=pv_name")
+                        .arg(format!("--select=vg_name={}", vg))
+                        .simple()
+                        .run_stdout()
+                        .context("Error listing LVM physical volumes")?,
+                ).context("LVM physical volume list isn't valid utf-8")?;
+            let pvs = pvs.lines().map(|l| PathBuf::from(l.trim())).collect::<HashSet<_>>();
+            return Ok(find_disks(&blocks, |b| pvs.contains(&b.path)));
+        },
+    }
+}
@@ -1,15 +1,20 @@
 use {
-    super::blockdev::LsblkDevice,
+    super::{
+        blockdev::LsblkDevice,
+        plan::Plan,
+        recovery,
+    },
     crate::{
-        blockdev::find_unused,
+        blockdev::{
+            find_disks,
+            find_unused,
+        },
         config::{
-            Config,
+            EncryptionMode,
             OUTER_UUID,
+            Volume,
         },
-        key::{
-            get_private_image_key,
-            get_shared_image_key,
-        },
+        key::get_key,
         util::SimpleCommandExt,
     },
     loga::{
@@ -31,7 +36,7 @@ use {
     },
 };
 
-fn mount(log: &Log, uuid: &str, mount_path: &PathBuf, key: Option<&String>) -> Result<(), loga::Error> {
+fn mount(log: &Log, plan: &Plan, uuid: &str, mount_path: &PathBuf, key: Option<&String>) -> Result<(), loga::Error> {
     let mut c = Command::new("bcachefs");
     c.arg("mount");
     c.arg("-o").arg("degraded,fsck,fix_errors");
@@ -39,22 +44,29 @@ fn mount(log: &Log, uuid: &str, mount_path: &PathBuf, key: Option<&String>) -> R
     if let Some(key) = key {
         c.arg("--key_location=stdin");
         log.log(loga::DEBUG, format!("Running {:?}", c));
-        c.simple().run_stdin(key.as_bytes()).context("Error mounting bcachefs")?;
+        c
+            .simple()
+            .apply_stdin(plan, format!("Mount bcachefs {} at {}", uuid, mount_path.dbg_str()), key.as_bytes())
+            .context("Error mounting bcachefs")?;
     } else {
         c.arg("--key_location=fail");
         log.log(loga::DEBUG, format!("Running {:?}", c));
-        c.simple().run().context("Error mounting bcachefs")?;
+        c
+            .simple()
+            .apply(plan, format!("Mount bcachefs {} at {}", uuid, mount_path.dbg_str()))
+            .context("Error mounting bcachefs")?;
     }
     return Ok(());
 }
 
 pub(crate) fn main(
     log: &Log,
+    plan: &Plan,
     blocks: Vec<LsblkDevice>,
-    config: &Config,
+    config: &Volume,
     mount_path: &PathBuf,
 ) -> Result<(), loga::Error> {
-    match main1(log, blocks, config, mount_path) {
+    match main1(log, plan, blocks, config, mount_path) {
         Ok(_) => {
             return Ok(());
         },
@@ -62,7 +74,7 @@ pub(crate) fn main(
             let mut c = Command::new("umount");
             c.arg("--lazy");
             c.arg(mount_path);
-            if let Err(e) = c.simple().run() {
+            if let Err(e) = c.simple().apply(plan, format!("Unmount {} after error", mount_path.dbg_str())) {
                 eprintln!("Warning: failed to unmount [{}] as cleanup after error: {}", mount_path.dbg_str(), e);
             }
             return Err(e);
@@ -72,8 +84,9 @@ pub(crate) fn main(
 
 pub(crate) fn main1(
     log: &Log,
+    plan: &Plan,
     blocks: Vec<LsblkDevice>,
-    config: &Config,
+    config: &Volume,
     mount_path: &PathBuf,
 ) -> Result<(), loga::Error> {
     let uuid = config.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
@@ -85,18 +98,8 @@ pub(crate) fn main1(
 
         // # Mount - can't add/remove until that's done
         let key;
-        match config.encryption.as_ref().unwrap_or(&crate::config::EncryptionMode::None {}) {
-            crate::config::EncryptionMode::None {} => {
-                key = None;
-            },
-            crate::config::EncryptionMode::DirectKey(enc_args) => {
-                key = Some(get_shared_image_key(&enc_args.key_mode, true)?);
-            },
-            crate::config::EncryptionMode::IndirectKey(enc_args) => {
-                key = Some(get_private_image_key(&log, &enc_args.key_path, &enc_args.key_mode)?);
-            },
-        }
-        mount(log, &uuid, &mount_path, key.as_ref())?;
+        key = get_key(log, plan, config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}), false)?;
+        mount(log, plan, &uuid, &mount_path, key.as_ref())?;
 
         // # Check current state
         let mut missing = vec![];
@@ -105,72 +108,92 @@ pub(crate) fn main1(
             // mountpoints in lsblk - so exclude those separately
             HashSet::new();
         let mut last_index = 0;
-        for d in read_dir(format!("/sys/fs/bcachefs/{}", uuid)).context("Error reading bcachefs sys dir")? {
-            let d = match d {
-                Ok(d) => d,
-                Err(e) => {
-                    log.log_err(loga::WARN, e.context("Error reading sysfs directory entry"));
-                    continue;
-                },
-            };
-            let name = match d.file_name().to_str().map(|x| x.to_string()) {
-                Some(n) => n,
-                None => {
-                    log.log_with(
-                        loga::WARN,
-                        "Error reading sysfs directory entry name as utf-8",
-                        ea!(name = String::from_utf8_lossy(d.file_name().as_bytes())),
-                    );
-                    continue;
-                },
-            };
-            let Some(index) = name.strip_prefix("dev-") else {
-                continue;
-            };
-            let index = match usize::from_str_radix(index, 10) {
-                Ok(i) => i,
-                Err(e) => {
-                    log.log_err(
-                        loga::WARN,
-                        e.context_with(
-                            "Error parsing device index from sysfs tree",
+        let sys_path = PathBuf::from(format!("/sys/fs/bcachefs/{}", uuid));
+        if plan.planning() && !sys_path.exists() {
+            // Not mounted yet so there's no sysfs tree - use the members visible in lsblk.
+            // Missing devices can't be determined until mounted.
+            for b in &blocks {
+                if b.uuid.as_ref().map(|u| u.as_str()) == Some(uuid) {
+                    used_extra.insert(b.path.file_name().unwrap().to_os_string());
+                    last_index += 1;
+                }
+            }
+        } else {
+            for d in read_dir(&sys_path).context("Error reading bcachefs sys dir")? {
+                let d = match d {
+                    Ok(d) => d,
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Error reading sysfs directory entry"));
+                        continue;
+                    },
+                };
+                let name = match d.file_name().to_str().map(|x| x.to_string()) {
+                    Some(n) => n,
+                    None => {
+                        log.log_with(
+                            loga::WARN,
+                            "Error reading sysfs directory entry name as utf-8",
                             ea!(name = String::from_utf8_lossy(d.file_name().as_bytes())),
-                        ),
-                    );
+                        );
+                        continue;
+                    },
+                };
+                let Some(index) = name.strip_prefix("dev-") else {
                     continue;
-                },
-            };
-            last_index = last_index.max(index);
-            if d.path().join("block").exists() {
-                used_extra.insert(
-                    read_link(d.path().join("block"))
-                        .context_with("Error reading bcachefs dev link", ea!(path = d.path().dbg_str()))?
-                        .file_name()
-                        .expect("Bcachefs dev link doesn't link to file")
-                        .to_os_string(),
-                );
-            } else {
-                missing.push(index);
+                };
+                let index = match usize::from_str_radix(index, 10) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        log.log_err(
+                            loga::WARN,
+                            e.context_with(
+                                "Error parsing device index from sysfs tree",
+                                ea!(name = String::from_utf8_lossy(d.file_name().as_bytes())),
+                            ),
+                        );
+                        continue;
+                    },
+                };
+                last_index = last_index.max(index);
+                if d.path().join("block").exists() {
+                    used_extra.insert(
+                        read_link(d.path().join("block"))
+                            .context_with("Error reading bcachefs dev link", ea!(path = d.path().dbg_str()))?
+                            .file_name()
+                            .expect("Bcachefs dev link doesn't link to file")
+                            .to_os_string(),
+                    );
+                } else {
+                    missing.push(index);
+                }
             }
         }
 
-        // # Add fresh devices
-        let unused = find_unused(blocks)?;
+        // # Add fresh devices - also excluding disks with members on partitions, which
+        // aren't in the sysfs tree by disk name
+        let members = find_disks(&blocks, |b| b.uuid.as_ref().map(|u| u.as_str()) == Some(uuid));
+        let blocks =
+            blocks
+                .into_iter()
+                .filter(|b| !used_extra.contains(b.path.file_name().unwrap()) && !members.contains(&b.path))
+                .collect();
+        let unused = find_unused(log, blocks, config.disks.as_ref())?;
         let mut added = false;
         for b in unused {
-            if used_extra.contains(b.path.file_name().unwrap()) {
-                continue;
-            }
             log.log(loga::INFO, format!("Adding new device [{}] to pool", b.path.dbg_str()));
+            plan.claim(&b.path);
             let hdd = b.rota.unwrap_or(true);
             let mut c = Command::new("bcachefs");
             last_index += 1;
             c.arg("device").arg("add").arg("--label").arg(format!("{}.d{}", match hdd {
                 true => "hdd",
                 false => "ssd",
-            }, last_index)).arg(&mount_path).arg(b.path);
+            }, last_index)).arg(&mount_path).arg(&b.path);
             log.log(loga::DEBUG, format!("Running {:?}", c));
-            c.simple().run().context("Error adding new device")?;
+            c
+                .simple()
+                .apply(plan, format!("Add device {} to pool", b.path.dbg_str()))
+                .context("Error adding new device")?;
             added = true;
         }
 
@@ -180,7 +203,10 @@ pub(crate) fn main1(
             let mut c = Command::new("bcachefs");
             c.arg("device").arg("remove").arg(index.to_string()).arg(mount_path);
             log.log(loga::DEBUG, format!("Running {:?}", c));
-            c.simple().run().context("Error removing failed/missing device")?;
+            c
+                .simple()
+                .apply(plan, format!("Remove lost device {} from pool", index))
+                .context("Error removing failed/missing device")?;
         }
 
         // # Replicate data with few replicas after disks were lost
@@ -189,7 +215,7 @@ pub(crate) fn main1(
             let mut c = Command::new("bcachefs");
             log.log(loga::DEBUG, format!("Running {:?}", c));
             c.arg("data").arg("rereplicate").arg(mount_path);
-            c.simple().run()?;
+            c.simple().apply(plan, "Rereplicate data")?;
         }
     } else {
         log.log(loga::INFO, format!("No filesystem found with UUID {} (show-super failed), creating", uuid));
@@ -206,23 +232,18 @@ pub(crate) fn main1(
                 .arg("--metadata_replicas_required=2")
                 .arg("--data_replicas_required=2")
                 .arg("--compression=zstd");
-            match config.encryption.as_ref().unwrap_or(&crate::config::EncryptionMode::None {}) {
-                crate::config::EncryptionMode::None {} => {
-                    key = None;
-                },
-                crate::config::EncryptionMode::DirectKey(enc_args) => {
-                    key = Some(get_shared_image_key(&enc_args.key_mode, true)?);
-                    c.arg("--encrypted");
-                },
-                crate::config::EncryptionMode::IndirectKey(enc_args) => {
-                    key = Some(get_private_image_key(&log, &enc_args.key_path, &enc_args.key_mode)?);
-                    c.arg("--encrypted");
-                },
+            key = get_key(log, plan, config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}), true)?;
+            let escrow = match (&config.recovery, &key) {
+                (Some(recovery_args), Some(_)) => Some(recovery::reserve(plan, recovery_args, &recovery_args.path)?),
+                _ => None,
+            };
+            if key.is_some() {
+                c.arg("--encrypted");
             }
             let mut label_id = 0;
             let mut has_hdd = false;
             let mut has_ssd = false;
-            let unused = find_unused(blocks)?;
+            let unused = find_unused(log, blocks, config.disks.as_ref())?;
             if unused.len() < 2 {
                 return Err(
                     loga::err(
@@ -232,6 +253,7 @@ pub(crate) fn main1(
             }
             for b in unused {
                 log.log(loga::INFO, format!("With volume [{}]", b.path.dbg_str()));
+                plan.claim(&b.path);
                 if b.rota.unwrap_or(true) {
                     c.arg(format!("--label=hdd.d{}", label_id)).arg(b.path);
                     label_id += 1;
@@ -250,13 +272,21 @@ pub(crate) fn main1(
             }
             log.log(loga::DEBUG, format!("Running {:?}", c));
             if let Some(key) = &key {
-                c.simple().run_stdin(key.as_bytes()).context("Error formatting bcachefs")?;
+                c
+                    .simple()
+                    .apply_stdin(plan, "Create bcachefs filesystem", key.as_bytes())
+                    .context("Error formatting bcachefs")?;
+
+                // Bcachefs only has one passphrase, so escrow that
+                if let Some(escrow) = escrow {
+                    escrow.write(log, plan, key)?;
+                }
             } else {
-                c.simple().run().context("Error formatting bcachefs")?;
+                c.simple().apply(plan, "Create bcachefs filesystem").context("Error formatting bcachefs")?;
             }
         }
         log.log(loga::INFO, format!("Mounting filesystem"));
-        mount(log, &uuid, &mount_path, key.as_ref())?;
+        mount(log, plan, &uuid, &mount_path, key.as_ref())?;
     }
     return Ok(());
 }
@@ -0,0 +1,278 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        plan::Plan,
+    },
+    crate::{
+        blockdev::{
+            find_disks,
+            find_unused,
+        },
+        config::{
+            BtrfsArgs,
+            BtrfsProfile,
+            EncryptionMode,
+            OUTER_UUID,
+            Volume,
+        },
+        util::SimpleCommandExt,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ErrContext,
+        Log,
+        ResultContext,
+    },
+    std::{
+        collections::HashSet,
+        fs::{
+            create_dir_all,
+            read_dir,
+            read_to_string,
+        },
+        os::unix::ffi::OsStrExt,
+        path::PathBuf,
+        process::Command,
+    },
+};
+
+fn profile_arg(profile: &BtrfsProfile) -> &'static str {
+    match profile {
+        BtrfsProfile::Single => "single",
+        BtrfsProfile::Raid1 => "raid1",
+        BtrfsProfile::Raid10 => "raid10",
+    }
+}
+
+fn profile_min_devices(profile: &BtrfsProfile) -> usize {
+    match profile {
+        BtrfsProfile::Single => 1,
+        BtrfsProfile::Raid1 => 2,
+        BtrfsProfile::Raid10 => 4,
+    }
+}
+
+fn mount(log: &Log, plan: &Plan, uuid: &str, mount_path: &PathBuf) -> Result<(), loga::Error> {
+    // Multi-device filesystems need all members registered with the kernel before
+    // mounting
+    let mut c = Command::new("btrfs");
+    c.arg("device").arg("scan");
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().apply(plan, "Scan for btrfs devices").context("Error scanning for btrfs devices")?;
+    let mut c = Command::new("mount");
+    c.arg("-t").arg("btrfs");
+    c.arg("-o").arg("degraded,noatime");
+    c.arg(format!("UUID={}", uuid)).arg(mount_path);
+    if plan.planned(format!("Mount btrfs {} at {}", uuid, mount_path.dbg_str()), Some(&c)) {
+        return Ok(());
+    }
+    create_dir_all(mount_path).context("Error creating mountpoint")?;
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().run().context("Error mounting btrfs")?;
+    return Ok(());
+}
+
+pub(crate) fn main(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    args: &BtrfsArgs,
+    mount_path: &PathBuf,
+) -> Result<(), loga::Error> {
+    match main1(log, plan, blocks, config, args, mount_path) {
+        Ok(_) => {
+            return Ok(());
+        },
+        Err(e) => {
+            let mut c = Command::new("umount");
+            c.arg("--lazy");
+            c.arg(mount_path);
+            if let Err(e) = c.simple().apply(plan, format!("Unmount {} after error", mount_path.dbg_str())) {
+                eprintln!("Warning: failed to unmount [{}] as cleanup after error: {}", mount_path.dbg_str(), e);
+            }
+            return Err(e);
+        },
+    }
+}
+
+pub(crate) fn main1(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    args: &BtrfsArgs,
+    mount_path: &PathBuf,
+) -> Result<(), loga::Error> {
+    match config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}) {
+        EncryptionMode::None {} => { },
+        _ => {
+            return Err(loga::err("Btrfs doesn't support encryption, use ext4 or bcachefs for encrypted volumes"));
+        },
+    }
+    let uuid = config.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
+    let data_profile = args.data_profile.as_ref().unwrap_or(&BtrfsProfile::Raid1);
+    let metadata_profile = args.metadata_profile.as_ref().unwrap_or(&BtrfsProfile::Raid1);
+    if blocks.iter().any(|b| b.uuid.as_ref().map(|u| u.as_str()) == Some(uuid)) {
+        log.log(loga::INFO, format!("Filesystem found with UUID {}, mounting", uuid));
+
+        // # Mount - can't add/remove until that's done
+        mount(log, plan, &uuid, &mount_path)?;
+
+        // # Check current state
+        let mut missing = vec![];
+        let mut devices = 0;
+        let mut used_extra =
+            // Only one member device of a multi-device filesystem shows a mountpoint in
+            // lsblk, so exclude the rest separately
+            HashSet::new();
+        if plan.planning() && !PathBuf::from(format!("/sys/fs/btrfs/{}", uuid)).exists() {
+            // Not mounted yet so there's no sysfs tree - use the members visible in lsblk.
+            // Missing devices can't be determined until mounted.
+            for b in &blocks {
+                if b.uuid.as_ref().map(|u| u.as_str()) == Some(uuid) {
+                    used_extra.insert(b.path.file_name().unwrap().to_os_string());
+                }
+            }
+        } else {
+            for d in read_dir(format!("/sys/fs/btrfs/{}/devices", uuid)).context("Error reading btrfs sys dir")? {
+                let d = match d {
+                    Ok(d) => d,
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Error reading sysfs directory entry"));
+                        continue;
+                    },
+                };
+                used_extra.insert(d.file_name());
+            }
+            for d in read_dir(format!("/sys/fs/btrfs/{}/devinfo", uuid)).context("Error reading btrfs sys dir")? {
+                let d = match d {
+                    Ok(d) => d,
+                    Err(e) => {
+                        log.log_err(loga::WARN, e.context("Error reading sysfs directory entry"));
+                        continue;
+                    },
+                };
+                let devid = match d.file_name().to_str().map(|x| x.to_string()) {
+                    Some(n) => n,
+                    None => {
+                        log.log_with(
+                            loga::WARN,
+                            "Error reading sysfs directory entry name as utf-8",
+                            ea!(name = String::from_utf8_lossy(d.file_name().as_bytes())),
+                        );
+                        continue;
+                    },
+                };
+                devices += 1;
+                let missing_path = d.path().join("missing");
+                let is_missing =
+                    read_to_string(
+                        &missing_path,
+                    ).context_with("Error reading btrfs device missing state", ea!(path = missing_path.dbg_str()))?;
+                if is_missing.trim() == "1" {
+                    missing.push(devid);
+                }
+            }
+        }
+
+        // # Add fresh devices - also excluding disks with members on partitions, which
+        // aren't in the sysfs tree by disk name
+        let members = find_disks(&blocks, |b| b.uuid.as_ref().map(|u| u.as_str()) == Some(uuid));
+        let blocks =
+            blocks
+                .into_iter()
+                .filter(|b| !used_extra.contains(b.path.file_name().unwrap()) && !members.contains(&b.path))
+                .collect();
+        let unused = find_unused(log, blocks, config.disks.as_ref())?;
+        let mut changed = false;
+        for b in unused {
+            log.log(loga::INFO, format!("Adding new device [{}] to pool", b.path.dbg_str()));
+            plan.claim(&b.path);
+            let mut c = Command::new("btrfs");
+            c.arg("device").arg("add").arg("--force").arg(&b.path).arg(&mount_path);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c
+                .simple()
+                .apply(plan, format!("Add device {} to pool", b.path.dbg_str()))
+                .context("Error adding new device")?;
+            devices += 1;
+            changed = true;
+        }
+
+        // # Remove dead/missing devices - only while enough devices remain for the
+        // profiles, otherwise stay mounted degraded until a replacement is added
+        let min_devices = profile_min_devices(data_profile).max(profile_min_devices(metadata_profile));
+        for devid in missing {
+            if devices <= min_devices {
+                log.log_with(
+                    loga::WARN,
+                    "Not enough devices to remove lost device, leaving filesystem degraded until a new disk is added",
+                    ea!(devid = devid, devices = devices, required = min_devices),
+                );
+                break;
+            }
+            log.log(loga::INFO, format!("Removing lost device [{}] from pool", devid));
+            let mut c = Command::new("btrfs");
+            c.arg("device").arg("remove").arg(&devid).arg(mount_path);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            if let Err(e) = c.simple().apply(plan, format!("Remove lost device {} from pool", devid)) {
+                log.log_err(
+                    loga::WARN,
+                    e.context_with("Error removing lost device, leaving filesystem degraded", ea!(devid = devid)),
+                );
+                break;
+            }
+            devices -= 1;
+            changed = true;
+        }
+
+        // # Restore the profiles for any chunks written while degraded. Chunks that
+        // already have the profile are skipped, so existing data isn't spread onto new
+        // disks. This can take a long time so it's left running in the background.
+        if changed {
+            log.log(loga::INFO, format!("Triggering rebalance"));
+            let mut c = Command::new("btrfs");
+            c
+                .arg("balance")
+                .arg("start")
+                .arg("--bg")
+                .arg(format!("-dconvert={},soft", profile_arg(data_profile)))
+                .arg(format!("-mconvert={},soft", profile_arg(metadata_profile)))
+                .arg(mount_path);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c.simple().apply(plan, "Rebalance data").context("Error rebalancing")?;
+        }
+    } else {
+        log.log(loga::INFO, format!("No filesystem found with UUID {}, creating", uuid));
+
+        // # New array
+        let mut c = Command::new("mkfs.btrfs");
+        c
+            .arg("--force")
+            .arg(format!("--uuid={}", uuid))
+            .arg(format!("--data={}", profile_arg(data_profile)))
+            .arg(format!("--metadata={}", profile_arg(metadata_profile)));
+        let min_devices = profile_min_devices(data_profile).max(profile_min_devices(metadata_profile));
+        let unused = find_unused(log, blocks, config.disks.as_ref())?;
+        if unused.len() < min_devices {
+            return Err(
+                loga::err_with(
+                    "No existing volume found, and insufficient unused block devices to create new volume with the configured profiles",
+                    ea!(found = unused.len(), required = min_devices),
+                ),
+            );
+        }
+        for b in unused {
+            log.log(loga::INFO, format!("With volume [{}]", b.path.dbg_str()));
+            plan.claim(&b.path);
+            c.arg(b.path);
+        }
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c.simple().apply(plan, "Create btrfs filesystem").context("Error formatting btrfs")?;
+        log.log(loga::INFO, format!("Mounting filesystem"));
+        mount(log, plan, &uuid, &mount_path)?;
+    }
+    return Ok(());
+}
@@ -1,28 +1,43 @@
 use {
-    super::blockdev::LsblkDevice,
+    super::{
+        blockdev::LsblkDevice,
+        header_backup,
+        luks,
+        partition,
+        plan::Plan,
+        raid,
+        recovery,
+    },
     crate::{
         blockdev::{
+            find_disks,
             find_unused,
+            is_blank,
+            require_data_disk,
+            signatures,
         },
         config::{
-            Config,
             EncryptionMode,
             INNER_UUID,
             OUTER_UUID,
+            Volume,
+            WipePolicy,
         },
         key::{
-            get_private_image_key,
-            get_shared_image_key,
+            get_all_keys,
+            with_key,
+            KeySource,
         },
         util::{
+            derive_uuid,
             from_utf8,
+            volume_name,
             SimpleCommandExt,
         },
     },
     flowcontrol::{
         shed,
         superif,
-        ta_return,
     },
     loga::{
         ea,
@@ -40,6 +55,7 @@ use {
     },
     std::{
         fs::{
+            canonicalize,
             File,
             OpenOptions,
         },
@@ -55,28 +71,109 @@ use {
     },
 };
 
+/// Name of the systemd mount unit for the mountpoint.
+pub(crate) fn mount_unit_name(mount_path: &Path) -> Result<String, loga::Error> {
+    let systemd_mount_name =
+        from_utf8(
+            Command::new("systemd-escape")
+                .arg("--path")
+                .arg("--suffix=mount")
+                .arg(&mount_path)
+                .simple()
+                .run_stdout()
+                .context("Error determining systemd mount name")?,
+        ).context("Systemd mount name via systemd-escape is not valid utf-8")?;
+    return Ok(systemd_mount_name.trim().to_string());
+}
+
+/// Mount the filesystem with `systemd-mount` if the mount unit isn't already active.
+pub(crate) fn ensure_mounted(log: &Log, plan: &Plan, fs_dev_path: &Path, mount_path: &Path) -> Result<(), loga::Error> {
+    let systemd_mount_name = mount_unit_name(mount_path)?;
+    let raw_active_state =
+        from_utf8(
+            Command::new("systemctl")
+                .arg("show")
+                .arg("--property=ActiveState")
+                .arg(&systemd_mount_name)
+                .simple()
+                .run_stdout()
+                .context("Error checking mount unit active state")?,
+        ).context("Mount unit active state isn't valid utf-8")?;
+    let Some((key, value)) = raw_active_state.trim().split_once("=") else {
+        return Err(
+            loga::err_with(
+                "Unable to parse mount unit active state",
+                ea!(unit = systemd_mount_name, raw_active_state = raw_active_state),
+            ),
+        );
+    };
+    if key != "ActiveState" {
+        return Err(
+            loga::err_with(
+                "Active state output has unexpected KV data",
+                ea!(unit = systemd_mount_name, raw_active_state = raw_active_state),
+            ),
+        );
+    }
+    if value != "active" {
+        log.log_with(
+            loga::INFO,
+            "Mounting filesystem",
+            ea!(dev = fs_dev_path.dbg_str(), mountpoint = mount_path.dbg_str(), unit_state = value),
+        );
+        Command::new("systemd-mount")
+            .arg("--options=noatime")
+            .arg("--collect")
+            .arg(fs_dev_path)
+            .arg(&mount_path)
+            .simple()
+            .apply(plan, format!("Mount {} at {}", fs_dev_path.dbg_str(), mount_path.dbg_str()))
+            .context("Failed to mount persistent disk")?;
+    }
+    return Ok(());
+}
+
 pub(crate) fn main(
     log: &Log,
+    plan: &Plan,
     blocks: Vec<LsblkDevice>,
-    config: &Config,
+    config: &Volume,
     mount_path: &PathBuf,
+) -> Result<(), loga::Error> {
+    return main_single(log, plan, blocks, config, mount_path, "ext4", |dev_path, uuid| {
+        let mut c = Command::new("mkfs.ext4");
+        c.arg("-F").arg(dev_path).arg("-U").arg(uuid);
+        return c;
+    });
+}
+
+/// The single-disk flow: select the largest disk, optionally set up LUKS, then
+/// format it with `mkfs` (given the device and filesystem UUID) and mount it.
+pub(crate) fn main_single(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    mount_path: &PathBuf,
+    fs_name: &str,
+    mkfs: impl Fn(&Path, &str) -> Command,
 ) -> Result<(), loga::Error> {
     let outer_uuid = config.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
     let outer_uuid_dev_path = PathBuf::from(format!("/dev/disk/by-uuid/{}", &outer_uuid));
-    let inner_uuid_dev_path = PathBuf::from(format!("/dev/disk/by-uuid/{}", &INNER_UUID));
+    let detached = config.luks_detached_header.as_ref();
+    let header = detached.map(|d| d.header.as_path());
 
     // Mounting - helper methods
     let format = |dev_path: &Path, uuid: &str| -> Result<PathBuf, loga::Error> {
-        log.log_with(loga::INFO, "Creating filesystem", ea!(dev = dev_path.dbg_str()));
-        Command::new("mkfs.ext4")
-            .arg("-F")
-            .arg(dev_path)
-            .arg("-U")
-            .arg(uuid)
+        log.log_with(loga::INFO, "Creating filesystem", ea!(dev = dev_path.dbg_str(), fs = fs_name));
+        mkfs(dev_path, uuid)
             .simple()
-            .run()
+            .apply(plan, format!("Create {} filesystem on {}", fs_name, dev_path.dbg_str()))
             .context("Error formatting persistent volume")?;
         let fs_dev_path = PathBuf::from(format!("/dev/disk/by-uuid/{}", uuid));
+        if plan.planning() {
+            return Ok(fs_dev_path);
+        }
         for _ in 0 .. 30 {
             if fs_dev_path.exists() {
                 return Ok(fs_dev_path);
@@ -85,87 +182,32 @@ pub(crate) fn main(
         }
         return Err(
             loga::err_with(
-                "Even after formatting disk ext4, it never appeared in `by-uuid`. Try wiping the disk to remove misleading headers or doing a health check.",
-                ea!(dev = dev_path.to_string_lossy(), path = fs_dev_path.to_string_lossy()),
+                "Even after formatting disk, it never appeared in `by-uuid`. Try wiping the disk to remove misleading headers or doing a health check.",
+                ea!(dev = dev_path.to_string_lossy(), fs = fs_name, path = fs_dev_path.to_string_lossy()),
             ),
         );
     };
-    let ensure_mounted = |fs_dev_path: &Path| {
-        ta_return!((), loga::Error);
-        let systemd_mount_name =
-            from_utf8(
-                Command::new("systemd-escape")
-                    .arg("--path")
-                    .arg("--suffix=mount")
-                    .arg(&mount_path)
-                    .simple()
-                    .run_stdout()
-                    .context("Error determining systemd mount name")?,
-            ).context("Systemd mount name via systemd-escape is not valid utf-8")?;
-        let raw_active_state =
-            from_utf8(
-                Command::new("systemctl")
-                    .arg("show")
-                    .arg("--property=ActiveState")
-                    .arg(systemd_mount_name.trim())
-                    .simple()
-                    .run_stdout()
-                    .context("Error checking mount unit active state")?,
-            ).context("Mount unit active state isn't valid utf-8")?;
-        let Some((key, value)) = raw_active_state.trim().split_once("=") else {
-            return Err(
-                loga::err_with(
-                    "Unable to parse mount unit active state",
-                    ea!(unit = systemd_mount_name, raw_active_state = raw_active_state),
-                ),
-            );
-        };
-        if key != "ActiveState" {
-            return Err(
-                loga::err_with(
-                    "Active state output has unexpected KV data",
-                    ea!(unit = systemd_mount_name, raw_active_state = raw_active_state),
-                ),
-            );
+    let ensure_map_luks = |dev_path: &Path, key: &str| -> Result<PathBuf, loga::Error> {
+        // Older versions opened volumes with a custom `uuid` as `persistent` too
+        let legacy_mapper_name = volume_name(OUTER_UUID);
+        if outer_uuid != OUTER_UUID && luks::is_mapped_from(&legacy_mapper_name, dev_path)? {
+            return Ok(PathBuf::from(format!("/dev/mapper/{}", legacy_mapper_name)));
         }
-        if value != "active" {
-            log.log_with(
-                loga::INFO,
-                "Mounting filesystem",
-                ea!(dev = fs_dev_path.dbg_str(), mountpoint = mount_path.dbg_str(), unit_state = value),
-            );
-            Command::new("systemd-mount")
-                .arg("--options=noatime")
-                .arg("--collect")
-                .arg(fs_dev_path)
-                .arg(&mount_path)
-                .simple()
-                .run()
-                .context("Failed to mount persistent disk")?;
-        }
-        return Ok(());
-    };
-    let ensure_map_luks = |key: &str| -> Result<PathBuf, loga::Error> {
-        let mapper_name = "persistent";
+        let mapper_name = volume_name(outer_uuid);
         let mapper_dev_path = PathBuf::from(format!("/dev/mapper/{}", mapper_name));
         if mapper_dev_path.exists() {
             return Ok(mapper_dev_path);
         }
-        log.log_with(loga::INFO, "Unlocking LUKS device", ea!(dev = outer_uuid_dev_path.dbg_str()));
-        Command::new("cryptsetup")
-            .arg("open")
-            .arg("--key-file=-")
-            .arg(&outer_uuid_dev_path)
-            .arg(mapper_name)
-            .simple()
-            .run_stdin(key.as_bytes())
-            .context("Error opening existing encrypted volume")?;
+        luks::open(log, plan, dev_path, header, &mapper_name, key)?;
         return Ok(mapper_dev_path);
     };
     let decrypt_extra = |key: &str, data_path: &Option<PathBuf>| -> Result<(), loga::Error> {
         if let Some(data_path) = data_path {
             let log = log.fork(ea!(path = data_path.dbg_str()));
             let decrypted_path = "/run/volumesetup_decrypted";
+            if plan.planned(format!("Decrypt {} to {}", data_path.dbg_str(), decrypted_path), None) {
+                return Ok(());
+            }
 
             struct Helper {
                 key: Password,
@@ -253,12 +295,50 @@ pub(crate) fn main(
 
     // Ensure mount
     superif!({
-        // Does the volume already exist?
-        for candidate in &blocks {
+        // With a detached header, the header existing means the volume exists. The data
+        // disk has no UUID (and a header device would be confused for the volume).
+        if let Some(detached) = detached {
+            if detached.header.exists() &&
+                Command::new("cryptsetup").arg("isLuks").arg(&detached.header).simple().run().is_ok() {
+                let data_disk =
+                    require_data_disk(
+                        &blocks,
+                        &detached.data_disk,
+                    ).context_with("Found detached LUKS header", ea!(header = detached.header.dbg_str()))?;
+                log.log_with(loga::INFO, "Found persistent disk", ea!(disk = data_disk.dbg_str()));
+                break 'exists_outer data_disk;
+            }
+        }
+
+        // Does the volume already exist? (Arrays are children of their member disks)
+        fn all_devices<'a>(blocks: &'a Vec<LsblkDevice>, out: &mut Vec<&'a LsblkDevice>) {
+            for candidate in blocks {
+                out.push(candidate);
+                all_devices(&candidate.children, out);
+            }
+        }
+
+        // When planning the array isn't assembled, so look for its members instead
+        let unassembled_array = match &config.raid {
+            Some(_) if plan.planning() => Some(raid::array(outer_uuid)?),
+            _ => None,
+        };
+        let mut all = vec![];
+        if detached.is_none() {
+            all_devices(&blocks, &mut all);
+        }
+        for candidate in all {
             let uuid = candidate.uuid.as_ref().map(|u| u.as_str());
             if uuid == Some(&outer_uuid) {
                 log.log_with(loga::INFO, "Found persistent disk", ea!(disk = candidate.path.dbg_str()));
-                break 'exists_outer candidate;
+                break 'exists_outer candidate.path.clone();
+            }
+            if let Some(array) = &unassembled_array {
+                if uuid == Some(&array.uuid) {
+                    log.log_with(loga::INFO, "Found RAID array member", ea!(disk = candidate.path.dbg_str()));
+                    raid::assemble(log, plan, array)?;
+                    break 'exists_outer array.dev_path.clone();
+                }
             }
             log.log_with(
                 loga::DEBUG,
@@ -268,7 +348,7 @@ pub(crate) fn main(
         }
 
         // Find existing volume, or candidate disk to format
-        let unused = find_unused(blocks)?;
+        let unused = find_unused(log, blocks.clone(), config.disks.as_ref())?;
         for candidate in &unused {
             log.log_with(
                 loga::INFO,
@@ -276,35 +356,117 @@ pub(crate) fn main(
                 ea!(disk = candidate.path.dbg_str(), size = candidate.size),
             );
         }
-        let best_candidate = unused.into_iter().next();
+        let escrow = match (&config.recovery, config.encryption.as_ref().unwrap_or(&EncryptionMode::None {})) {
+            (Some(recovery_args), enc) if !matches!(enc, EncryptionMode::None {}) => {
+                Some(recovery::reserve(plan, recovery_args, &recovery_args.path)?)
+            },
+            _ => None,
+        };
+        let target_path = match &config.raid {
+            None if detached.is_some() => {
+                let detached = detached.unwrap();
+                let data_disk = require_data_disk(&blocks, &detached.data_disk)?;
+                if find_disks(&blocks, |b| b.mountpoints.iter().any(|p| p.is_some())).contains(&data_disk) {
+                    return Err(loga::err_with("The data disk is in use", ea!(disk = data_disk.dbg_str())));
+                }
 
-        // Didn't find existing volume, so format the best candidate volume
-        let candidate = best_candidate.context("Couldn't find persistent disk or a suitable candidate for formatting")?;
-        log.log_with(
-            loga::INFO,
-            "Couldn't find persistent disk, formatting best attached candidate disk",
-            ea!(disk = candidate.path.dbg_str()),
-        );
-        let setup_encrypted = |key: &str| -> Result<(), loga::Error> {
-            log.log_with(loga::INFO, "Initializing LUKS device", ea!(dev = candidate.path.dbg_str()));
-            Command::new("cryptsetup")
-                .arg("luksFormat")
-                .arg("--type=luks2")
-                .arg("--key-file=-")
-                .arg(&candidate.path)
-                .simple()
-                .run_stdin(key.as_bytes())
-                .context("Error encypting new volume on persistent disk")?;
+                // If the header was lost the data disk looks like any other encrypted disk, so
+                // don't overwrite it unless explicitly allowed
+                if !is_blank(&data_disk)? {
+                    match config.disks.as_ref().and_then(|d| d.allow_wipe.as_ref()) {
+                        Some(WipePolicy::Allow) => {
+                            log.log_with(
+                                loga::WARN,
+                                "Data disk isn't blank, it will be overwritten",
+                                ea!(disk = data_disk.dbg_str()),
+                            );
+                        },
+                        _ => {
+                            return Err(
+                                loga::err_with(
+                                    "No LUKS header found and the data disk isn't blank, refusing to overwrite it. If this is a new volume set `allow_wipe` to `allow`.",
+                                    ea!(disk = data_disk.dbg_str(), header = detached.header.dbg_str()),
+                                ),
+                            );
+                        },
+                    }
+                }
+                log.log_with(
+                    loga::INFO,
+                    "Couldn't find detached LUKS header, formatting data disk",
+                    ea!(disk = data_disk.dbg_str()),
+                );
+                data_disk
+            },
+            Some(raid_args) => {
+                // Didn't find existing volume, so build an array from all candidate volumes
+                log.log(loga::INFO, "Couldn't find persistent disk, creating array from attached candidate disks");
+                raid::create(log, plan, &raid::array(outer_uuid)?, raid_args, unused)?
+            },
+            None if config.partition.is_some() => {
+                partition::create(
+                    log,
+                    plan,
+                    outer_uuid,
+                    config.partition.as_ref().unwrap(),
+                    unused,
+                    blocks.clone(),
+                    config.disks.as_ref(),
+                )?
+            },
+            None => {
+                let best_candidate = unused.into_iter().next();
+
+                // Didn't find existing volume, so format the best candidate volume
+                let candidate =
+                    best_candidate.context("Couldn't find persistent disk or a suitable candidate for formatting")?;
+                log.log_with(
+                    loga::INFO,
+                    "Couldn't find persistent disk, formatting best attached candidate disk",
+                    ea!(disk = candidate.path.dbg_str()),
+                );
+                candidate.path
+            },
+        };
+        plan.claim(&target_path);
+        let setup_encrypted = |keys: &[(KeySource, String)]| -> Result<(), loga::Error> {
+            luks::format(
+                log,
+                plan,
+                &target_path,
+                header,
+                config.luks_format.as_ref(),
+                keys,
+            ).context("Error encrypting new volume on persistent disk")?;
+
+            // Keyslots and metadata are in the header
+            let header_path = header.unwrap_or(&target_path);
+            if let Some(escrow) = escrow {
+                luks::add_key(
+                    log,
+                    plan,
+                    header_path,
+                    config.luks_format.as_ref(),
+                    &keys[0].1,
+                    &escrow.create(log, plan)?,
+                ).context("Error enrolling recovery key")?;
+            }
             Command::new("cryptsetup")
                 .arg("luksUUID")
                 .arg("--uuid")
                 .arg(&outer_uuid)
-                .arg(&candidate.path)
+                .arg(header_path)
                 .simple()
-                .run()
+                .apply(plan, format!("Set LUKS UUID on {}", header_path.dbg_str()))
                 .context("Error setting UUID on newly encrypted volume on persistent disk")?;
+            if let Some(backup_args) = &config.luks_header_backup {
+                header_backup::backup(log, plan, backup_args, header_path, outer_uuid)?;
+            }
             shed!{
                 'exists_outer1 _;
+                if plan.planning() || header.is_some() {
+                    break 'exists_outer1;
+                }
                 for _ in 0 .. 30 {
                     if outer_uuid_dev_path.exists() {
                         break 'exists_outer1;
@@ -318,61 +480,103 @@ pub(crate) fn main(
                     ),
                 );
             }
-            let luks_dev_path = ensure_map_luks(&key).context("Error mapping new LUKS volume")?;
-            let fs_dev_path = format(&luks_dev_path, INNER_UUID)?;
-            ensure_mounted(&fs_dev_path)?;
+            let luks_dev_path =
+                ensure_map_luks(
+                    match header {
+                        Some(_) => &target_path,
+                        None => &outer_uuid_dev_path,
+                    },
+                    &keys[0].1,
+                ).context("Error mapping new LUKS volume")?;
+            let fs_dev_path = format(&luks_dev_path, &derive_uuid(outer_uuid, INNER_UUID)?)?;
+            ensure_mounted(log, plan, &fs_dev_path, mount_path)?;
             return Ok(());
         };
-        match config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}) {
-            EncryptionMode::None {} => {
-                let fs_dev_path = format(&PathBuf::from(&candidate.path), &outer_uuid)?;
-                ensure_mounted(&fs_dev_path)?;
-            },
-            EncryptionMode::DirectKey(enc_args) => {
-                let key = get_shared_image_key(&enc_args.key_mode, true)?;
-                setup_encrypted(&key)?;
-            },
-            EncryptionMode::IndirectKey(enc_args) => {
-                let key = get_private_image_key(&log, &enc_args.key_path, &enc_args.key_mode)?;
-                setup_encrypted(&key)?;
-                decrypt_extra(&key, &enc_args.decrypt)?;
-            },
+        let keys = get_all_keys(log, plan, config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}))?;
+        if keys.is_empty() {
+            let fs_dev_path = format(&target_path, &outer_uuid)?;
+            ensure_mounted(log, plan, &fs_dev_path, mount_path)?;
+        } else {
+            setup_encrypted(&keys)?;
+            if let Some((source, key)) = keys.iter().find(|(source, _)| source.decrypt().is_some()) {
+                decrypt_extra(key, source.decrypt())?;
+            }
+        }
+    } dev_path = 'exists_outer {
+        if config.raid.is_some() {
+            // The array's own members have signatures, so exclude them rather than warning
+            // about them
+            let array = raid::array(outer_uuid)?;
+            let members = find_disks(&blocks, |b| b.uuid.as_ref() == Some(&array.uuid));
+            let blocks = blocks.iter().filter(|b| !members.contains(&b.path)).cloned().collect();
+            let unused = find_unused(log, blocks, config.disks.as_ref())?;
+            raid::repair(log, plan, &array, unused)?;
         }
-    } candidate = 'exists_outer {
+
         // Found existing volume, just mount it
-        let mount_encrypted = |key: &str| -> Result<(), loga::Error> {
-            let luks_dev_path = ensure_map_luks(key)?;
+        let mount_encrypted = |luks_dev_path: &Path| -> Result<(), loga::Error> {
+            let inner_uuid = derive_uuid(outer_uuid, INNER_UUID)?;
+            let inner_uuid_dev_path = PathBuf::from(format!("/dev/disk/by-uuid/{}", &inner_uuid));
             let fs_dev_path = shed!{
                 'exists_inner1 _;
+                if plan.planning() {
+                    // Can't look inside the LUKS volume until it's opened
+                    break 'exists_inner1 inner_uuid_dev_path;
+                }
+
+                // Volumes with a custom `uuid` created by older versions have the default inner
+                // UUID, which may also belong to another volume so make sure it's this one
+                let luks_dev_path_real =
+                    canonicalize(
+                        luks_dev_path,
+                    ).context_with("Error resolving LUKS device path", ea!(path = luks_dev_path.dbg_str()))?;
+                let candidates =
+                    [inner_uuid_dev_path.clone(), PathBuf::from(format!("/dev/disk/by-uuid/{}", INNER_UUID))];
                 for _ in 0 .. 30 {
-                    if inner_uuid_dev_path.exists() {
-                        break 'exists_inner1 inner_uuid_dev_path;
+                    for candidate in &candidates {
+                        if canonicalize(candidate).ok().as_ref() == Some(&luks_dev_path_real) {
+                            break 'exists_inner1 candidate.clone();
+                        }
                     }
                     sleep(Duration::from_secs(1));
                 }
+
+                // Only format if the LUKS volume is empty, otherwise it's someone's data
+                let found = signatures(luks_dev_path)?;
+                if !found.is_empty() {
+                    return Err(
+                        loga::err_with(
+                            "Filesystem with UUID never appeared but the LUKS volume isn't empty, refusing to format it",
+                            ea!(dev = luks_dev_path.dbg_str(), uuid = inner_uuid, signatures = found.join(", ")),
+                        ),
+                    );
+                }
                 log.log_with(
                     loga::INFO,
-                    "Filesystem with UUID never appeared; assuming formatting never completed.",
+                    "Filesystem with UUID never appeared and LUKS volume is empty; assuming formatting never completed.",
                     ea!(dev = inner_uuid_dev_path.dbg_str()),
                 );
-                break 'exists_inner1 format(&luks_dev_path, INNER_UUID)?;
+                break 'exists_inner1 format(luks_dev_path, &inner_uuid)?;
             };
-            ensure_mounted(&fs_dev_path)?;
+            ensure_mounted(log, plan, &fs_dev_path, mount_path)?;
             return Ok(());
         };
-        match config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}) {
-            EncryptionMode::None {} => {
-                let fs_dev_path = PathBuf::from(&candidate.path);
-                ensure_mounted(&fs_dev_path)?;
-            },
-            EncryptionMode::DirectKey(enc_args) => {
-                let key = get_shared_image_key(&enc_args.key_mode, false)?;
-                mount_encrypted(&key)?;
+        let luks_source_path = match header {
+            Some(_) => &dev_path,
+            None => &outer_uuid_dev_path,
+        };
+        match with_key(
+            log,
+            plan,
+            config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}),
+            |key| ensure_map_luks(luks_source_path, key),
+        )? {
+            None => {
+                ensure_mounted(log, plan, &dev_path, mount_path)?;
             },
-            EncryptionMode::IndirectKey(enc_args) => {
-                let key = get_private_image_key(&log, &enc_args.key_path, &enc_args.key_mode)?;
-                mount_encrypted(&key)?;
-                decrypt_extra(&key, &enc_args.decrypt)?;
+            Some((source, key, luks_dev_path)) => {
+                mount_encrypted(&luks_dev_path)?;
+                decrypt_extra(&key, source.decrypt())?;
             },
         }
     });
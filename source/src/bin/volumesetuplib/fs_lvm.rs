@@ -0,0 +1,285 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        fs_ext4::ensure_mounted,
+        header_backup,
+        luks,
+        plan::Plan,
+        recovery,
+    },
+    crate::{
+        blockdev::find_unused,
+        config::{
+            EncryptionMode,
+            LvmArgs,
+            LvmFilesystem,
+            Volume,
+        },
+        key::{
+            get_all_keys,
+            with_key,
+        },
+        util::{
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    path_absolutize::Absolutize,
+    std::{
+        collections::HashSet,
+        fs::create_dir_all,
+        path::PathBuf,
+        process::{
+            Command,
+            Stdio,
+        },
+        thread::sleep,
+        time::Duration,
+    },
+};
+
+fn wait_exists(path: &PathBuf) -> Result<(), loga::Error> {
+    for _ in 0 .. 30 {
+        if path.exists() {
+            return Ok(());
+        }
+        sleep(Duration::from_secs(1));
+    }
+    return Err(loga::err_with("Device never appeared", ea!(path = path.dbg_str())));
+}
+
+pub(crate) fn main(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    args: &LvmArgs,
+) -> Result<(), loga::Error> {
+    let vg = args.volume_group.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+
+    // # Ensure volume group
+    let vg_exists = Command::new("vgs").arg(vg).simple().run().is_ok();
+
+    // Physical volumes of any volume group don't have mountpoints in lsblk until the
+    // logical volumes are mounted, so exclude them separately
+    let pvs =
+        from_utf8(
+            Command::new("pvs")
+                .arg("--noheadings")
+                .arg("--options=pv_name")
+                .simple()
+                .run_stdout()
+                .context("Error listing LVM physical volumes")?,
+        ).context("LVM physical volume list isn't valid utf-8")?;
+    let pvs = pvs.lines().map(|l| PathBuf::from(l.trim())).collect::<HashSet<_>>();
+    let blocks = blocks.into_iter().filter(|b| !pvs.contains(&b.path)).collect();
+    let unused = find_unused(log, blocks, config.disks.as_ref())?;
+    if vg_exists {
+        log.log(loga::INFO, format!("Volume group {} found, activating", vg));
+        let mut c = Command::new("vgchange");
+        c.arg("--activate").arg("y").arg(vg);
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c.simple().apply(plan, format!("Activate volume group {}", vg)).context("Error activating volume group")?;
+        for b in unused {
+            log.log(loga::INFO, format!("Adding new device [{}] to volume group", b.path.dbg_str()));
+            plan.claim(&b.path);
+            let mut c = Command::new("vgextend");
+            c.arg("--yes").arg(vg).arg(&b.path);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c
+                .simple()
+                .apply(plan, format!("Add device {} to volume group {}", b.path.dbg_str(), vg))
+                .context("Error adding new device to volume group")?;
+        }
+    } else {
+        log.log(loga::INFO, format!("No volume group found with name {}, creating", vg));
+        if unused.is_empty() {
+            return Err(loga::err("No existing volume group found, and no unused block devices to create one"));
+        }
+        let mut c = Command::new("vgcreate");
+        c.arg("--yes").arg(vg);
+        for b in unused {
+            log.log(loga::INFO, format!("With volume [{}]", b.path.dbg_str()));
+            plan.claim(&b.path);
+            c.arg(b.path);
+        }
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c.simple().apply(plan, format!("Create volume group {}", vg)).context("Error creating volume group")?;
+    }
+
+    // # Ensure logical volumes
+    let mut key = None;
+    let mut format_keys = None;
+    for lv in &args.volumes {
+        let log = log.fork(ea!(lv = lv.name));
+        let mount_path =
+            lv.mountpoint.absolutize().context("Couldn't make logical volume mountpoint absolute")?.into_owned();
+        let lv_dev_path = PathBuf::from(format!("/dev/{}/{}", vg, lv.name));
+        let mut new = false;
+        // The device only exists once the volume group is activated, which doesn't happen
+        // when planning
+        if !lv_dev_path.exists() &&
+            Command::new("lvs").arg(format!("{}/{}", vg, lv.name)).simple().run().is_err() {
+            log.log(loga::INFO, "Creating logical volume");
+            let mut c = Command::new("lvcreate");
+            c.arg("--yes").arg(format!("--name={}", lv.name));
+            if lv.size.contains("%") {
+                c.arg(format!("--extents={}", lv.size));
+            } else {
+                c.arg(format!("--size={}", lv.size));
+            }
+            c.arg(vg);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c
+                .simple()
+                .apply(plan, format!("Create logical volume {}", lv.name))
+                .context("Error creating logical volume")?;
+            if !plan.planning() {
+                wait_exists(&lv_dev_path)?;
+            }
+            new = true;
+        }
+
+        // Encryption
+        let fs_dev_path;
+        match config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}) {
+            EncryptionMode::None {} => {
+                fs_dev_path = lv_dev_path.clone();
+            },
+            enc => {
+                let mapper_name = format!("{}-{}-crypt", vg, lv.name);
+                fs_dev_path = PathBuf::from(format!("/dev/mapper/{}", mapper_name));
+                let is_luks = if lv_dev_path.exists() {
+                    Command::new("cryptsetup").arg("isLuks").arg(&lv_dev_path).simple().run().is_ok()
+                } else {
+                    // Only when planning - the volume group isn't active yet, so assume existing
+                    // logical volumes were already set up
+                    !new
+                };
+                if !is_luks {
+                    if format_keys.is_none() {
+                        format_keys = Some(get_all_keys(&log, plan, enc)?);
+                    }
+                    let format_keys = format_keys.as_ref().unwrap();
+
+                    // Logical volumes may be created on different boots, so each gets its own
+                    // recovery key
+                    let escrow = match &config.recovery {
+                        Some(recovery_args) => Some(
+                            recovery::reserve(plan, recovery_args, &recovery::lv_path(recovery_args, &lv.name))?,
+                        ),
+                        None => None,
+                    };
+                    luks::format(
+                        &log,
+                        plan,
+                        &lv_dev_path,
+                        None,
+                        config.luks_format.as_ref(),
+                        format_keys,
+                    ).context("Error encrypting new logical volume")?;
+                    if let Some(escrow) = escrow {
+                        luks::add_key(
+                            &log,
+                            plan,
+                            &lv_dev_path,
+                            config.luks_format.as_ref(),
+                            &format_keys[0].1,
+                            &escrow.create(&log, plan)?,
+                        ).context("Error enrolling recovery key")?;
+                    }
+                    if let Some(backup_args) = &config.luks_header_backup {
+                        header_backup::backup(&log, plan, backup_args, &lv_dev_path, &format!("{}-{}", vg, lv.name))?;
+                    }
+                    if key.is_none() {
+                        key = Some(format_keys[0].1.clone());
+                    }
+                    new = true;
+                }
+                if !fs_dev_path.exists() {
+                    match &key {
+                        Some(key) => {
+                            // All logical volumes are set up with the same keys, reuse the one that
+                            // worked
+                            luks::open(&log, plan, &lv_dev_path, None, &mapper_name, key)?;
+                        },
+                        None => {
+                            let (_, found_key, _) =
+                                with_key(
+                                    &log,
+                                    plan,
+                                    enc,
+                                    |key| luks::open(&log, plan, &lv_dev_path, None, &mapper_name, key),
+                                )?.unwrap();
+                            key = Some(found_key);
+                        },
+                    }
+                }
+            },
+        }
+
+        // Filesystem - `blkid` exits with 2 if it finds no signature, other failures
+        // mean it couldn't check. When planning, the device may not be open yet so this
+        // can't be checked.
+        let mut create_fs = new;
+        if !new && fs_dev_path.exists() {
+            let status =
+                Command::new("blkid")
+                    .arg(&fs_dev_path)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .context_with("Error running blkid", ea!(dev = fs_dev_path.dbg_str()))?;
+            match status.code() {
+                Some(0) => { },
+                Some(2) => {
+                    create_fs = true;
+                },
+                _ => {
+                    return Err(
+                        loga::err_with(
+                            "Error probing logical volume for an existing filesystem",
+                            ea!(dev = fs_dev_path.dbg_str(), code = status.code().dbg_str()),
+                        ),
+                    );
+                },
+            }
+        }
+        if create_fs {
+            log.log_with(loga::INFO, "Creating filesystem", ea!(dev = fs_dev_path.dbg_str()));
+            let mut c;
+            match lv.fs {
+                LvmFilesystem::Ext4 => {
+                    c = Command::new("mkfs.ext4");
+                    c.arg("-F").arg(&fs_dev_path);
+                },
+                LvmFilesystem::Xfs => {
+                    c = Command::new("mkfs.xfs");
+                    c.arg("-f").arg(&fs_dev_path);
+                },
+            }
+            c
+                .simple()
+                .apply(plan, format!("Create filesystem on {}", fs_dev_path.dbg_str()))
+                .context("Error formatting logical volume")?;
+        }
+        ensure_mounted(&log, plan, &fs_dev_path, &mount_path)?;
+
+        // Ensure subdirectories in mountpoint
+        for path in lv.ensure_dirs.iter().flatten() {
+            if plan.planned(format!("Create directory {}", mount_path.join(&path).dbg_str()), None) {
+                continue;
+            }
+            create_dir_all(
+                &mount_path.join(&path),
+            ).stack_context_with(&log, "Failed to create mount point subidr", ea!(subdir = path.to_string_lossy()))?;
+        }
+    }
+    return Ok(());
+}
@@ -0,0 +1,27 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        fs_ext4::main_single,
+        plan::Plan,
+    },
+    crate::config::Volume,
+    loga::Log,
+    std::{
+        path::PathBuf,
+        process::Command,
+    },
+};
+
+pub(crate) fn main(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    mount_path: &PathBuf,
+) -> Result<(), loga::Error> {
+    return main_single(log, plan, blocks, config, mount_path, "xfs", |dev_path, uuid| {
+        let mut c = Command::new("mkfs.xfs");
+        c.arg("-f").arg("-m").arg(format!("uuid={}", uuid)).arg(dev_path);
+        return c;
+    });
+}
@@ -0,0 +1,358 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        plan::Plan,
+        recovery,
+    },
+    crate::{
+        blockdev::find_unused,
+        config::{
+            EncryptionMode,
+            Volume,
+            ZfsArgs,
+            ZfsLayout,
+        },
+        key::get_key,
+        util::{
+            from_utf8,
+            is_mounted,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        collections::HashSet,
+        fs::create_dir_all,
+        path::PathBuf,
+        process::Command,
+    },
+};
+
+fn layout_arg(layout: &ZfsLayout) -> &'static str {
+    match layout {
+        ZfsLayout::Mirror => "mirror",
+        ZfsLayout::Raidz1 => "raidz1",
+        ZfsLayout::Raidz2 => "raidz2",
+    }
+}
+
+fn layout_min_devices(layout: &ZfsLayout) -> usize {
+    match layout {
+        ZfsLayout::Mirror => 2,
+        ZfsLayout::Raidz1 => 3,
+        ZfsLayout::Raidz2 => 4,
+    }
+}
+
+/// Does the device or any of its children (zfs partitions whole disks) belong to a
+/// pool in `guids`?
+fn is_member(candidate: &LsblkDevice, guids: &HashSet<String>) -> bool {
+    if candidate.fstype.as_ref().map(|x| x.as_str()) == Some("zfs_member") {
+        if let Some(guid) = &candidate.uuid {
+            if guids.contains(guid) {
+                return true;
+            }
+        }
+    }
+    for child in &candidate.children {
+        if is_member(child, guids) {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Find the GUIDs of all pools with the given name on attached devices.
+fn find_pool_guids(blocks: &Vec<LsblkDevice>, pool: &str, out: &mut HashSet<String>) {
+    for candidate in blocks {
+        if candidate.fstype.as_ref().map(|x| x.as_str()) == Some("zfs_member") &&
+            candidate.label.as_ref().map(|x| x.as_str()) == Some(pool) {
+            if let Some(guid) = &candidate.uuid {
+                out.insert(guid.clone());
+            }
+        }
+        find_pool_guids(&candidate.children, pool, out);
+    }
+}
+
+/// Returns the GUIDs of leaf vdevs that are no longer usable.
+fn find_faulted(pool: &str) -> Result<Vec<String>, loga::Error> {
+    let raw =
+        from_utf8(
+            Command::new("zpool")
+                .arg("status")
+                .arg("-g")
+                .arg(pool)
+                .simple()
+                .run_stdout()
+                .context("Error getting pool status")?,
+        ).context("Pool status isn't valid utf-8")?;
+
+    // Rows in the config tree, as (indent, name, state)
+    let mut rows = vec![];
+    let mut in_config = false;
+    for line in raw.lines() {
+        if !in_config {
+            if line.trim_start().starts_with("NAME") {
+                in_config = true;
+            }
+            continue;
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+        let indent = line.len() - line.trim_start().len();
+        let mut parts = line.split_whitespace();
+        let (Some(name), Some(state)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        rows.push((indent, name.to_string(), state.to_string()));
+    }
+    let mut out = vec![];
+    for (i, (indent, name, state)) in rows.iter().enumerate().skip(1) {
+        let leaf = match rows.get(i + 1) {
+            Some((next_indent, _, _)) => next_indent <= indent,
+            None => true,
+        };
+        if !leaf {
+            continue;
+        }
+        match state.as_str() {
+            "FAULTED" | "UNAVAIL" | "REMOVED" => {
+                out.push(name.clone());
+            },
+            _ => { },
+        }
+    }
+    return Ok(out);
+}
+
+/// Mount the pool unless it's already mounted (zfs mounts don't show up in lsblk,
+/// so this isn't caught before setup). Sets `mounted` if this mounted it.
+fn mount(log: &Log, plan: &Plan, pool: &str, mount_path: &PathBuf, mounted: &mut bool) -> Result<(), loga::Error> {
+    if is_mounted(mount_path) {
+        log.log(loga::INFO, "Already mounted, not mounting again.");
+        return Ok(());
+    }
+    let mut c = Command::new("mount");
+    c.arg("-t").arg("zfs");
+    c.arg(pool).arg(mount_path);
+    if plan.planned(format!("Mount zfs pool {} at {}", pool, mount_path.dbg_str()), Some(&c)) {
+        return Ok(());
+    }
+    create_dir_all(mount_path).context("Error creating mountpoint")?;
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().run().context("Error mounting zfs")?;
+    *mounted = true;
+    return Ok(());
+}
+
+pub(crate) fn main(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    args: &ZfsArgs,
+    mount_path: &PathBuf,
+) -> Result<(), loga::Error> {
+    let mut mounted = false;
+    match main1(log, plan, blocks, config, args, mount_path, &mut mounted) {
+        Ok(_) => {
+            return Ok(());
+        },
+        Err(e) => {
+            // Leave the pool alone if it was already mounted before this run
+            if !mounted {
+                return Err(e);
+            }
+            let mut c = Command::new("umount");
+            c.arg("--lazy");
+            c.arg(mount_path);
+            if let Err(e) = c.simple().apply(plan, format!("Unmount {} after error", mount_path.dbg_str())) {
+                eprintln!("Warning: failed to unmount [{}] as cleanup after error: {}", mount_path.dbg_str(), e);
+            }
+            return Err(e);
+        },
+    }
+}
+
+pub(crate) fn main1(
+    log: &Log,
+    plan: &Plan,
+    blocks: Vec<LsblkDevice>,
+    config: &Volume,
+    args: &ZfsArgs,
+    mount_path: &PathBuf,
+    mounted: &mut bool,
+) -> Result<(), loga::Error> {
+    let pool = args.pool.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+    let layout = args.layout.as_ref().unwrap_or(&ZfsLayout::Mirror);
+    let mut guids = HashSet::new();
+    find_pool_guids(&blocks, pool, &mut guids);
+    let imported = Command::new("zpool").arg("list").arg(pool).simple().run().is_ok();
+    if imported || !guids.is_empty() {
+        log.log(loga::INFO, format!("Pool found with name {}, importing", pool));
+
+        // # Import and unlock
+        if !imported {
+            if guids.len() > 1 {
+                return Err(
+                    loga::err_with(
+                        "Found multiple pools with the configured name, refusing to guess which to import",
+                        ea!(pool = pool, guids = guids.dbg_str()),
+                    ),
+                );
+            }
+            let guid = guids.iter().next().unwrap();
+            let mut c = Command::new("zpool");
+            c.arg("import").arg("-N").arg(guid);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c
+                .simple()
+                .apply(plan, format!("Import pool {}", guid))
+                .context_with("Error importing pool", ea!(guid = guid))?;
+        } else {
+            let guid =
+                from_utf8(
+                    Command::new("zpool")
+                        .arg("get")
+                        .arg("-H")
+                        .arg("-p")
+                        .arg("-o")
+                        .arg("value")
+                        .arg("guid")
+                        .arg(pool)
+                        .simple()
+                        .run_stdout()
+                        .context("Error getting imported pool GUID")?,
+                ).context("Pool GUID isn't valid utf-8")?;
+            guids = HashSet::from([guid.trim().to_string()]);
+        }
+        let key_status = if plan.planning() && !imported {
+            // Can't be checked until imported, assume the key needs to be loaded
+            String::new()
+        } else {
+            from_utf8(
+                Command::new("zfs")
+                    .arg("get")
+                    .arg("-H")
+                    .arg("-o")
+                    .arg("value")
+                    .arg("keystatus")
+                    .arg(pool)
+                    .simple()
+                    .run_stdout()
+                    .context("Error getting pool key status")?,
+            ).context("Pool key status isn't valid utf-8")?
+        };
+        if key_status.trim() != "available" {
+            let key;
+            key = get_key(log, plan, config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}), false)?;
+            if let Some(key) = &key {
+                let mut c = Command::new("zfs");
+                c.arg("load-key").arg(pool);
+                log.log(loga::DEBUG, format!("Running {:?}", c));
+                c.simple().apply_stdin(plan, "Load pool key", key.as_bytes()).context("Error loading pool key")?;
+            }
+        }
+        mount(log, plan, pool, mount_path, mounted)?;
+
+        // # Replace faulted devices
+        let faulted = if plan.planning() && !imported {
+            // Can't be checked until imported
+            vec![]
+        } else {
+            find_faulted(pool)?
+        };
+        if !faulted.is_empty() {
+            // Pool members don't have mountpoints in lsblk, so exclude them separately
+            let blocks = blocks.into_iter().filter(|b| !is_member(b, &guids)).collect();
+            let mut unused = find_unused(log, blocks, config.disks.as_ref())?.into_iter();
+            for vdev in faulted {
+                let Some(b) = unused.next() else {
+                    log.log_with(
+                        loga::WARN,
+                        "No unused disk available to replace faulted device",
+                        ea!(vdev = vdev),
+                    );
+                    continue;
+                };
+                log.log(loga::INFO, format!("Replacing faulted device [{}] with [{}]", vdev, b.path.dbg_str()));
+                plan.claim(&b.path);
+                let mut c = Command::new("zpool");
+                c.arg("replace").arg("-f").arg(pool).arg(&vdev).arg(&b.path);
+                log.log(loga::DEBUG, format!("Running {:?}", c));
+                c
+                    .simple()
+                    .apply(plan, format!("Replace faulted device {} with {}", vdev, b.path.dbg_str()))
+                    .context("Error replacing faulted device")?;
+            }
+        }
+    } else {
+        log.log(loga::INFO, format!("No pool found with name {}, creating", pool));
+
+        // # New pool
+        let mut c = Command::new("zpool");
+        c
+            .arg("create")
+            .arg("-f")
+            .arg("-o")
+            .arg("ashift=12")
+            .arg("-O")
+            .arg("mountpoint=legacy")
+            .arg("-O")
+            .arg("atime=off")
+            .arg("-O")
+            .arg("compression=zstd");
+        let key;
+        key = get_key(log, plan, config.encryption.as_ref().unwrap_or(&EncryptionMode::None {}), true)?;
+        let escrow = match (&config.recovery, &key) {
+            (Some(recovery_args), Some(_)) => Some(recovery::reserve(plan, recovery_args, &recovery_args.path)?),
+            _ => None,
+        };
+        if key.is_some() {
+            c
+                .arg("-O")
+                .arg("encryption=on")
+                .arg("-O")
+                .arg("keyformat=passphrase")
+                .arg("-O")
+                .arg("keylocation=prompt");
+        }
+        c.arg(pool).arg(layout_arg(layout));
+        let min_devices = layout_min_devices(layout);
+        let unused = find_unused(log, blocks, config.disks.as_ref())?;
+        if unused.len() < min_devices {
+            return Err(
+                loga::err_with(
+                    "No existing pool found, and insufficient unused block devices to create new pool with the configured layout",
+                    ea!(found = unused.len(), required = min_devices),
+                ),
+            );
+        }
+        for b in unused {
+            log.log(loga::INFO, format!("With volume [{}]", b.path.dbg_str()));
+            plan.claim(&b.path);
+            c.arg(b.path);
+        }
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        if let Some(key) = &key {
+            c.simple().apply_stdin(plan, "Create pool", key.as_bytes()).context("Error creating pool")?;
+
+            // ZFS only has one passphrase, so escrow that
+            if let Some(escrow) = escrow {
+                escrow.write(log, plan, key)?;
+            }
+        } else {
+            c.simple().apply(plan, "Create pool").context("Error creating pool")?;
+        }
+        log.log(loga::INFO, format!("Mounting filesystem"));
+        mount(log, plan, pool, mount_path, mounted)?;
+    }
+    return Ok(());
+}
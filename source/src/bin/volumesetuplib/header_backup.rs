@@ -0,0 +1,77 @@
+use {
+    super::{
+        plan::Plan,
+        recovery,
+    },
+    crate::{
+        config::HeaderBackupArgs,
+        util::SimpleCommandExt,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        fs::{
+            create_dir_all,
+            read,
+            remove_dir_all,
+            write,
+            DirBuilder,
+        },
+        os::unix::fs::DirBuilderExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Back up the LUKS header of the device, encrypted to the configured recipients.
+/// `name` identifies the device in the backup directory.
+pub(crate) fn backup(
+    log: &Log,
+    plan: &Plan,
+    args: &HeaderBackupArgs,
+    dev_path: &Path,
+    name: &str,
+) -> Result<(), loga::Error> {
+    let backup_path = args.dir.join(format!("{}.luksheader.{}", name, recovery::extension(&args.recipients)));
+    if plan.planned(
+        format!("Back up LUKS header of {} to {}", dev_path.dbg_str(), backup_path.dbg_str()),
+        None,
+    ) {
+        return Ok(());
+    }
+    log.log_with(
+        loga::INFO,
+        "Backing up LUKS header",
+        ea!(dev = dev_path.dbg_str(), path = backup_path.dbg_str()),
+    );
+
+    // `cryptsetup` creates the backup file itself (and refuses to overwrite an
+    // existing one), so it goes in a private directory since the unencrypted header
+    // has keyslot material
+    let raw_dir = PathBuf::from("/run/volumesetup_luksheader");
+    if raw_dir.exists() {
+        remove_dir_all(&raw_dir).context_with("Error removing stale header backup", ea!(path = raw_dir.dbg_str()))?;
+    }
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&raw_dir)
+        .context_with("Error creating header backup directory", ea!(path = raw_dir.dbg_str()))?;
+    let raw_path = raw_dir.join("header");
+    let mut c = Command::new("cryptsetup");
+    c.arg("luksHeaderBackup").arg(dev_path).arg("--header-backup-file").arg(&raw_path);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    let res = c.simple().run().context("Error backing up LUKS header");
+    let raw = res.and_then(|_| read(&raw_path).context("Error reading LUKS header backup"));
+    remove_dir_all(&raw_dir).context_with("Error removing unencrypted header backup", ea!(path = raw_dir.dbg_str()))?;
+    let encrypted = recovery::encrypt(&args.recipients, &raw?).context("Error encrypting LUKS header backup")?;
+    create_dir_all(&args.dir).context_with("Error creating header backup directory", ea!(path = args.dir.dbg_str()))?;
+    write(&backup_path, encrypted).context_with("Error writing LUKS header backup", ea!(path = backup_path.dbg_str()))?;
+    return Ok(());
+}
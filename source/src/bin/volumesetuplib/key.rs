@@ -1,9 +1,21 @@
 use {
+    super::{
+        plan::Plan,
+        shamir,
+        tang::get_tang_key,
+        tpm2::get_tpm2_key,
+    },
     crate::{
         config::{
-            PinMode,
+            DirectKeyArgs,
+            EncryptionMode,
+            IndirectKeyArgs,
             PrivateImageKeyMode,
             SharedImageKeyMode,
+            TangKeyArgs,
+            ThresholdKeyArgs,
+            Tpm2KeyArgs,
+            UnlockMethod,
         },
         util::{
             from_utf8,
@@ -16,30 +28,62 @@ use {
         Log,
         ResultContext,
     },
-    openpgp_card_rpgp::CardSlot,
-    pcsc::Context,
-    pgp::Deserializable,
     rand::{
-        prelude::SliceRandom,
         thread_rng,
+        RngCore,
     },
-    std::{
-        collections::{
-            HashMap,
-            HashSet,
+    sequoia_openpgp::{
+        crypto::Password,
+        parse::{
+            stream::DecryptorBuilder,
+            Parse,
         },
+        policy::StandardPolicy,
+    },
+    std::{
         fs::read,
         io::{
             stdin,
             Read,
         },
-        path::Path,
+        path::{
+            Path,
+            PathBuf,
+        },
         process::Command,
+    },
+};
+#[cfg(feature = "age")]
+use super::age_key;
+#[cfg(feature = "smartcard")]
+use {
+    crate::config::PinMode,
+    openpgp_card_rpgp::CardSlot,
+    pcsc::Context,
+    pgp::Deserializable,
+    rand::prelude::SliceRandom,
+    std::{
+        collections::{
+            HashMap,
+            HashSet,
+        },
         thread::sleep,
-        time::Duration,
+        time::{
+            Duration,
+            Instant,
+        },
     },
 };
 
+/// How long to wait for a smartcard to be presented before giving up, so other
+/// unlock methods can be tried.
+#[cfg(feature = "smartcard")]
+const SMARTCARD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How many times a bad PIN or failed card read is retried before giving up.
+#[cfg(feature = "smartcard")]
+const SMARTCARD_ATTEMPTS: usize = 3;
+
 pub(crate) fn ask_password(message: &str) -> Result<String, loga::Error> {
     let raw =
         Command::new("systemd-ask-password")
@@ -52,7 +96,15 @@ pub(crate) fn ask_password(message: &str) -> Result<String, loga::Error> {
     return Ok(from_utf8(raw).context("Received password was invalid utf8")?.trim().to_string());
 }
 
-pub(crate) fn get_shared_image_key(key_mode: &SharedImageKeyMode, confirm: bool) -> Result<String, loga::Error> {
+pub(crate) fn get_shared_image_key(
+    plan: &Plan,
+    key_mode: &SharedImageKeyMode,
+    confirm: bool,
+) -> Result<String, loga::Error> {
+    if plan.planning() {
+        // Nothing is encrypted or unlocked when planning
+        return Ok(String::new());
+    }
     match key_mode {
         SharedImageKeyMode::Stdin => {
             let mut data = Vec::new();
@@ -88,24 +140,236 @@ pub(crate) fn get_shared_image_key(key_mode: &SharedImageKeyMode, confirm: bool)
 
 pub(crate) fn get_private_image_key(
     log: &Log,
+    plan: &Plan,
     key_path: &Path,
     key_mode: &PrivateImageKeyMode,
 ) -> Result<String, loga::Error> {
-    let encrypted =
-        pgp::Message::from_armor_single(
-            &mut read(key_path)
-                .context_with("Error reading encrypted key", ea!(path = key_path.to_string_lossy()))?
-                .as_slice(),
-        )
-            .context("Encrypted data isn't valid ASCII Armor")?
-            .0;
+    if plan.planning() {
+        // Nothing is encrypted or unlocked when planning
+        return Ok(String::new());
+    }
+    let mut decrypted = decrypt_private(log, &[key_path], key_mode, |_| Ok(true))?;
+    return Ok(decrypted.remove(0).1);
+}
+
+/// Load the encryption keys from an OpenPGP secret key file, asking for the
+/// passphrase.
+fn load_secret_key(path: &Path) -> Result<Vec<sequoia_openpgp::crypto::KeyPair>, loga::Error> {
+    let policy = StandardPolicy::new();
+    let cert =
+        sequoia_openpgp::Cert::from_file(
+            path,
+        ).map_err(
+            |e| loga::err(e.to_string()).context_with("Error reading secret key", ea!(path = path.dbg_str())),
+        )?;
+    let mut warning = None;
+    'passphrase : loop {
+        let mut prompt = String::new();
+        if let Some(warning) = warning.take() {
+            prompt.push_str(warning);
+        }
+        prompt.push_str("Enter the secret key passphrase");
+        let password = Password::from(ask_password(&prompt)?);
+        let mut keypairs = vec![];
+        for key in cert.keys().with_policy(&policy, None).secret().for_transport_encryption().for_storage_encryption() {
+            let mut key = key.key().clone();
+            if key.secret().is_encrypted() {
+                key = match key.decrypt_secret(&password) {
+                    Ok(k) => k,
+                    Err(_) => {
+                        warning = Some("Incorrect passphrase, please try again.\n");
+                        continue 'passphrase;
+                    },
+                };
+            }
+            keypairs.push(
+                key
+                    .into_keypair()
+                    .map_err(
+                        |e| loga::err(
+                            e.to_string(),
+                        ).context_with("Error loading secret key", ea!(path = path.dbg_str())),
+                    )?,
+            );
+        }
+        if keypairs.is_empty() {
+            return Err(loga::err_with("Secret key has no valid encryption keys", ea!(path = path.dbg_str())));
+        }
+        return Ok(keypairs);
+    }
+}
+
+fn decrypt_with_keypairs(
+    key_path: &Path,
+    keypairs: Vec<sequoia_openpgp::crypto::KeyPair>,
+) -> Result<String, loga::Error> {
+    struct Helper {
+        keypairs: Vec<sequoia_openpgp::crypto::KeyPair>,
+    }
+
+    impl sequoia_openpgp::parse::stream::DecryptionHelper for Helper {
+        fn decrypt<
+            D,
+        >(
+            &mut self,
+            pkesks: &[sequoia_openpgp::packet::PKESK],
+            _skesks: &[sequoia_openpgp::packet::SKESK],
+            sym_algo: Option<sequoia_openpgp::types::SymmetricAlgorithm>,
+            mut decrypt: D,
+        ) -> sequoia_openpgp::Result<Option<sequoia_openpgp::Fingerprint>>
+        where
+            D:
+                FnMut(
+                    sequoia_openpgp::types::SymmetricAlgorithm,
+                    &sequoia_openpgp::crypto::SessionKey,
+                ) -> bool {
+            for pkesk in pkesks {
+                for pair in &mut self.keypairs {
+                    let Some((algo, sk)) = pkesk.decrypt(pair, sym_algo) else {
+                        continue;
+                    };
+                    if decrypt(algo, &sk) {
+                        return Ok(Some(pair.public().fingerprint()));
+                    }
+                }
+            }
+            return Err(
+                sequoia_openpgp::Error::MissingSessionKey("Not encrypted to the secret key".to_string()).into(),
+            );
+        }
+    }
+
+    impl sequoia_openpgp::parse::stream::VerificationHelper for Helper {
+        fn get_certs(
+            &mut self,
+            _ids: &[sequoia_openpgp::KeyHandle],
+        ) -> sequoia_openpgp::Result<Vec<sequoia_openpgp::Cert>> {
+            return Ok(Vec::new());
+        }
+
+        fn check(&mut self, _structure: sequoia_openpgp::parse::stream::MessageStructure) -> sequoia_openpgp::Result<()> {
+            return Ok(());
+        }
+    }
+
+    let mut decrypted = vec![];
+    DecryptorBuilder::from_file(key_path)
+        .map_err(
+            |e| loga::err(e.to_string()).context_with("Error reading encrypted key", ea!(path = key_path.dbg_str())),
+        )?
+        .with_policy(&StandardPolicy::new(), None, Helper { keypairs })
+        .map_err(|e| loga::err(e.to_string()).context_with("Decryption failed", ea!(path = key_path.dbg_str())))?
+        .read_to_end(&mut decrypted)
+        .context_with("Error reading decrypted key", ea!(path = key_path.dbg_str()))?;
+    return Ok(from_utf8(decrypted).context("Key file contains invalid utf-8")?.trim().to_string());
+}
+
+/// Get the volume key by decrypting key shares (each encrypted to a different
+/// administrator) until there are enough to combine.
+pub(crate) fn get_threshold_key(
+    log: &Log,
+    plan: &Plan,
+    share_paths: &[PathBuf],
+    key_mode: &PrivateImageKeyMode,
+) -> Result<String, loga::Error> {
+    if plan.planning() {
+        // Nothing is encrypted or unlocked when planning
+        return Ok(String::new());
+    }
+    let share_paths = share_paths.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+    let decrypted = decrypt_private(log, &share_paths, key_mode, |decrypted| {
+        let threshold = shamir::share_threshold(&decrypted[0].1)?;
+        if decrypted.len() < threshold {
+            log.log(
+                loga::INFO,
+                format!(
+                    "Read key share {} of {}, the next administrator should unlock their share",
+                    decrypted.len(),
+                    threshold
+                ),
+            );
+            return Ok(false);
+        }
+        return Ok(true);
+    })?;
+    return Ok(shamir::combine(&decrypted.into_iter().map(|(_, share)| share).collect::<Vec<_>>())?);
+}
+
+/// For modes where a single unlock can decrypt all the files - decrypt each until
+/// `enough` returns true.
+fn decrypt_each(
+    key_paths: &[&Path],
+    enough: impl Fn(&[(usize, String)]) -> Result<bool, loga::Error>,
+    decrypt: impl Fn(&Path) -> Result<String, loga::Error>,
+) -> Result<Vec<(usize, String)>, loga::Error> {
+    let mut decrypted = vec![];
+    let mut errors = vec![];
+    for (i, key_path) in key_paths.iter().enumerate() {
+        match decrypt(key_path) {
+            Ok(data) => {
+                decrypted.push((i, data));
+                if enough(&decrypted)? {
+                    return Ok(decrypted);
+                }
+            },
+            Err(e) => {
+                errors.push(e);
+            },
+        }
+    }
+    return Err(loga::agg_err("Couldn't decrypt enough keys", errors));
+}
+
+/// Decrypt the files at `key_paths`, one per unlock (ex: smartcard tap), until
+/// `enough` returns true. Returns the index of the path and the decrypted
+/// contents of each decrypted file.
+fn decrypt_private(
+    log: &Log,
+    key_paths: &[&Path],
+    key_mode: &PrivateImageKeyMode,
+    enough: impl Fn(&[(usize, String)]) -> Result<bool, loga::Error>,
+) -> Result<Vec<(usize, String)>, loga::Error> {
     match key_mode {
+        PrivateImageKeyMode::SecretKey { secret_key_path } => {
+            log.log_with(loga::INFO, "Decrypting with secret key", ea!(path = secret_key_path.dbg_str()));
+            let keypairs = load_secret_key(secret_key_path)?;
+            return decrypt_each(key_paths, enough, |key_path| decrypt_with_keypairs(key_path, keypairs.clone()));
+        },
+        #[cfg(feature = "age")]
+        PrivateImageKeyMode::Age { identity } => {
+            log.log(loga::INFO, "Decrypting with age identity");
+            let identity = age_key::load_identity(identity)?;
+            return decrypt_each(key_paths, enough, |key_path| age_key::decrypt(key_path, &identity));
+        },
         #[cfg(feature = "smartcard")]
         PrivateImageKeyMode::Smartcard { pin } => {
+            let mut decrypted: Vec<(usize, String)> = vec![];
+            let mut encrypted = vec![];
+            for key_path in key_paths {
+                encrypted.push(
+                    pgp::Message::from_armor_single(
+                        &mut read(key_path)
+                            .context_with("Error reading encrypted key", ea!(path = key_path.to_string_lossy()))?
+                            .as_slice(),
+                    )
+                        .context_with(
+                            "Encrypted data isn't valid ASCII Armor",
+                            ea!(path = key_path.to_string_lossy()),
+                        )?
+                        .0,
+                );
+            }
             let mut pcsc_context =
                 pcsc::Context::establish(pcsc::Scope::User).context("Error setting up PCSC context")?;
             let mut watch: Vec<pcsc::ReaderState> = vec![];
-            loop {
+            let mut deadline = Instant::now() + SMARTCARD_TIMEOUT;
+            let mut failures = 0;
+            'pin : loop {
+                if failures >= SMARTCARD_ATTEMPTS {
+                    return Err(
+                        loga::err_with("Giving up on smartcard after too many failed attempts", ea!(attempts = failures)),
+                    );
+                }
                 let pin = match &pin {
                     PinMode::FactoryDefault => "123456".to_string(),
                     PinMode::Text => ask_password("Enter your PIN")?,
@@ -149,6 +413,10 @@ pub(crate) fn get_private_image_key(
                                 let d = match digit_lookup.get(&c) {
                                     Some(d) => **d,
                                     None => {
+                                        failures += 1;
+                                        if failures >= SMARTCARD_ATTEMPTS {
+                                            continue 'pin;
+                                        }
                                         warning = Some("There were invalid digits in the PIN. Please try again.\n");
                                         continue 'retry;
                                     },
@@ -161,10 +429,19 @@ pub(crate) fn get_private_image_key(
                 };
                 if pin.is_empty() {
                     log.log(loga::WARN, "Got empty pin, please retry");
+                    failures += 1;
                     sleep(Duration::from_secs(1));
                     continue;
                 }
                 loop {
+                    if Instant::now() >= deadline {
+                        return Err(
+                            loga::err_with(
+                                "Timed out waiting for smartcard",
+                                ea!(timeout_secs = SMARTCARD_TIMEOUT.as_secs()),
+                            ),
+                        );
+                    }
                     let mut reader_names = pcsc_context.list_readers_owned()?.into_iter().collect::<HashSet<_>>();
                     reader_names.insert(pcsc::PNP_NOTIFICATION().to_owned());
                     let mut i = 0;
@@ -223,10 +500,25 @@ pub(crate) fn get_private_image_key(
                                             openpgp_card::ocard::KeyType::Decryption,
                                             &|| { },
                                         ).context("Error turning card into decryption key")?;
-                                    let decrypted =
-                                        match card_key
-                                            .decrypt_message(&encrypted)
-                                            .context("Failed to decrypt disk secret - wrong key device?")? {
+                                    let mut errors = vec![];
+                                    for (i, encrypted) in encrypted.iter().enumerate() {
+                                        if decrypted.iter().any(|(j, _)| *j == i) {
+                                            continue;
+                                        }
+                                        let message =
+                                            match card_key
+                                                .decrypt_message(encrypted)
+                                                .context_with(
+                                                    "Error decrypting",
+                                                    ea!(path = key_paths[i].to_string_lossy()),
+                                                ) {
+                                                Ok(m) => m,
+                                                Err(e) => {
+                                                    errors.push(e);
+                                                    continue;
+                                                },
+                                            };
+                                        let data = match message {
                                             pgp::Message::Literal(l) => l.data().to_vec(),
                                             other => {
                                                 return Err(
@@ -237,19 +529,44 @@ pub(crate) fn get_private_image_key(
                                                 );
                                             },
                                         };
-                                    log.log(loga::INFO, "Done reading smartcard, you may now remove it");
-                                    return Ok(
-                                        from_utf8(decrypted)
-                                            .context("Key file contains invalid utf-8")?
-                                            .trim()
-                                            .to_string(),
+                                        log.log(loga::INFO, "Done reading smartcard, you may now remove it");
+                                        return Ok(
+                                            (
+                                                i,
+                                                from_utf8(data)
+                                                    .context("Key file contains invalid utf-8")?
+                                                    .trim()
+                                                    .to_string(),
+                                            ),
+                                        );
+                                    }
+                                    return Err(
+                                        loga::agg_err("Failed to decrypt disk secret - wrong key device?", errors),
                                     );
                                 })() {
-                                    Ok(key) => {
-                                        return Ok(key);
+                                    Ok(found) => {
+                                        decrypted.push(found);
+                                        if enough(&decrypted)? {
+                                            return Ok(decrypted);
+                                        }
+
+                                        // Each share is unlocked by a different card, with its own PIN
+                                        state.sync_current_state();
+                                        deadline = Instant::now() + SMARTCARD_TIMEOUT;
+                                        failures = 0;
+                                        continue 'pin;
                                     },
                                     Err(e) => {
+                                        // Most likely a wrong PIN, so ask again
+                                        failures += 1;
+                                        if failures >= SMARTCARD_ATTEMPTS {
+                                            return Err(
+                                                e.context("Giving up on smartcard after too many failed attempts"),
+                                            );
+                                        }
                                         log.log_err(loga::WARN, e.context("Failed to get volume key, retrying"));
+                                        state.sync_current_state();
+                                        continue 'pin;
                                     },
                                 }
                             }
@@ -262,3 +579,169 @@ pub(crate) fn get_private_image_key(
         },
     }
 }
+
+/// Generate a key for methods where the key isn't provided by the user (the key
+/// is stored sealed or encrypted instead).
+pub(crate) fn new_random_key() -> String {
+    let mut raw = [0u8; 32];
+    thread_rng().fill_bytes(&mut raw);
+    return raw.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+}
+
+/// One way of getting a key to unlock a volume.
+#[derive(Clone, Copy)]
+pub(crate) enum KeySource<'a> {
+    Direct(&'a DirectKeyArgs),
+    Indirect(&'a IndirectKeyArgs),
+    Threshold(&'a ThresholdKeyArgs),
+    Tpm2(&'a Tpm2KeyArgs),
+    Tang(&'a TangKeyArgs),
+}
+
+impl<'a> KeySource<'a> {
+    /// Additional data to decrypt once unlocked.
+    pub(crate) fn decrypt(&self) -> &'a Option<PathBuf> {
+        match self {
+            KeySource::Direct(_) | KeySource::Tpm2(_) | KeySource::Tang(_) => &None,
+            KeySource::Indirect(args) => &args.decrypt,
+            KeySource::Threshold(args) => &args.decrypt,
+        }
+    }
+}
+
+/// The ways to unlock the volume, in the order they should be tried. Empty if
+/// unencrypted.
+pub(crate) fn key_sources(mode: &EncryptionMode) -> Result<Vec<KeySource<'_>>, loga::Error> {
+    match mode {
+        EncryptionMode::None {} => {
+            return Ok(vec![]);
+        },
+        EncryptionMode::DirectKey(enc_args) => {
+            return Ok(vec![KeySource::Direct(enc_args)]);
+        },
+        EncryptionMode::IndirectKey(enc_args) => {
+            return Ok(vec![KeySource::Indirect(enc_args)]);
+        },
+        EncryptionMode::ThresholdKey(enc_args) => {
+            return Ok(vec![KeySource::Threshold(enc_args)]);
+        },
+        EncryptionMode::Tpm2Key(enc_args) => {
+            return Ok(vec![KeySource::Tpm2(enc_args)]);
+        },
+        EncryptionMode::TangKey(enc_args) => {
+            return Ok(vec![KeySource::Tang(enc_args)]);
+        },
+        EncryptionMode::AnyOf(methods) => {
+            // Would be treated as unencrypted
+            if methods.is_empty() {
+                return Err(loga::err("`any_of` needs at least one unlock method"));
+            }
+            return Ok(methods.iter().map(|m| match m {
+                UnlockMethod::DirectKey(args) => KeySource::Direct(args),
+                UnlockMethod::IndirectKey(args) => KeySource::Indirect(args),
+                UnlockMethod::ThresholdKey(args) => KeySource::Threshold(args),
+                UnlockMethod::Tpm2Key(args) => KeySource::Tpm2(args),
+                UnlockMethod::TangKey(args) => KeySource::Tang(args),
+            }).collect());
+        },
+    }
+}
+
+/// `confirm` is set when the key will be used to initialize a volume - passwords
+/// are asked for twice, and a new TPM2/Tang key is created if there isn't one yet.
+pub(crate) fn get_source_key(log: &Log, plan: &Plan, source: KeySource, confirm: bool) -> Result<String, loga::Error> {
+    match source {
+        KeySource::Direct(args) => {
+            return Ok(get_shared_image_key(plan, &args.key_mode, confirm)?);
+        },
+        KeySource::Indirect(args) => {
+            return Ok(get_private_image_key(log, plan, &args.key_path, &args.key_mode)?);
+        },
+        KeySource::Threshold(args) => {
+            return Ok(get_threshold_key(log, plan, &args.share_paths, &args.key_mode)?);
+        },
+        KeySource::Tpm2(args) => {
+            return Ok(get_tpm2_key(log, plan, args, confirm)?);
+        },
+        KeySource::Tang(args) => {
+            return Ok(get_tang_key(log, plan, args, confirm)?);
+        },
+    }
+}
+
+/// Get the key for the encryption mode, or `None` if unencrypted. For
+/// filesystems with native encryption, which only support a single key.
+pub(crate) fn get_key(
+    log: &Log,
+    plan: &Plan,
+    mode: &EncryptionMode,
+    confirm: bool,
+) -> Result<Option<String>, loga::Error> {
+    let sources = key_sources(mode)?;
+    match sources.as_slice() {
+        [] => {
+            return Ok(None);
+        },
+        [source] => {
+            return Ok(Some(get_source_key(log, plan, *source, confirm)?));
+        },
+        _ => {
+            return Err(loga::err("Multiple unlock methods are only supported with LUKS (`ext4`, `xfs`, `lvm`)"));
+        },
+    }
+}
+
+/// Get the keys for all unlock methods, to initialize a volume with.
+pub(crate) fn get_all_keys<'a>(
+    log: &Log,
+    plan: &Plan,
+    mode: &'a EncryptionMode,
+) -> Result<Vec<(KeySource<'a>, String)>, loga::Error> {
+    let mut out = vec![];
+    for (i, source) in key_sources(mode)?.into_iter().enumerate() {
+        let key = get_source_key(log, plan, source, true).context_with("Error getting key", ea!(unlock_method = i))?;
+        out.push((source, key));
+    }
+    return Ok(out);
+}
+
+/// Try each unlock method in order until `f` succeeds with the key, falling back
+/// to the next if getting the key or `f` fails. Returns `None` if unencrypted.
+pub(crate) fn with_key<'a, T>(
+    log: &Log,
+    plan: &Plan,
+    mode: &'a EncryptionMode,
+    mut f: impl FnMut(&str) -> Result<T, loga::Error>,
+) -> Result<Option<(KeySource<'a>, String, T)>, loga::Error> {
+    let sources = key_sources(mode)?;
+    if sources.is_empty() {
+        return Ok(None);
+    }
+    let mut errors = vec![];
+    for (i, source) in sources.into_iter().enumerate() {
+        let key = match get_source_key(log, plan, source, false) {
+            Ok(k) => k,
+            Err(e) => {
+                log.log_err(
+                    loga::WARN,
+                    e.clone().context_with("Error getting key, trying next unlock method", ea!(unlock_method = i)),
+                );
+                errors.push(e);
+                continue;
+            },
+        };
+        match f(&key) {
+            Ok(v) => {
+                return Ok(Some((source, key, v)));
+            },
+            Err(e) => {
+                log.log_err(
+                    loga::WARN,
+                    e.clone().context_with("Error unlocking with key, trying next unlock method", ea!(unlock_method = i)),
+                );
+                errors.push(e);
+            },
+        }
+    }
+    return Err(loga::agg_err("All unlock methods failed", errors));
+}
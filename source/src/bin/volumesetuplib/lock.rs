@@ -0,0 +1,153 @@
+use {
+    super::{
+        fs_ext4::mount_unit_name,
+        luks,
+        plan::Plan,
+        raid,
+    },
+    crate::{
+        config::{
+            EncryptionMode,
+            FilesystemMode,
+            OUTER_UUID,
+            Volume,
+        },
+        util::{
+            from_utf8,
+            is_mounted,
+            volume_mount_path,
+            volume_name,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    path_absolutize::Absolutize,
+    std::{
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// Unmount the mountpoint, stopping the mount unit if it was mounted with
+/// `systemd-mount`.
+fn unmount(log: &Log, plan: &Plan, mount_path: &Path) -> Result<(), loga::Error> {
+    if !is_mounted(mount_path) {
+        return Ok(());
+    }
+    let unit = mount_unit_name(mount_path)?;
+    let active_state =
+        from_utf8(
+            Command::new("systemctl")
+                .arg("show")
+                .arg("--property=ActiveState")
+                .arg("--value")
+                .arg(&unit)
+                .simple()
+                .run_stdout()
+                .context("Error checking mount unit active state")?,
+        ).context("Mount unit active state isn't valid utf-8")?;
+    let mut c;
+    if active_state.trim() == "active" {
+        c = Command::new("systemctl");
+        c.arg("stop").arg(&unit);
+    } else {
+        c = Command::new("umount");
+        c.arg(mount_path);
+    }
+    log.log(loga::INFO, format!("Unmounting [{}]", mount_path.dbg_str()));
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().apply(plan, format!("Unmount {}", mount_path.dbg_str())).context("Error unmounting volume")?;
+    return Ok(());
+}
+
+fn close_luks(log: &Log, plan: &Plan, mapper_name: &str) -> Result<(), loga::Error> {
+    if !PathBuf::from(format!("/dev/mapper/{}", mapper_name)).exists() {
+        return Ok(());
+    }
+    log.log(loga::INFO, format!("Closing LUKS device [{}]", mapper_name));
+    let mut c = Command::new("cryptsetup");
+    c.arg("close").arg(mapper_name);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().apply(plan, format!("Close LUKS device {}", mapper_name)).context("Error closing LUKS device")?;
+    return Ok(());
+}
+
+/// Undo setup: unmount the volume, close any LUKS devices, and stop/export the
+/// underlying array, pool or volume group so the disks can be detached.
+pub(crate) fn lock_volume(log: &Log, plan: &Plan, volume: &Volume) -> Result<(), loga::Error> {
+    let uuid = volume.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
+    let encrypted = match volume.encryption.as_ref().unwrap_or(&EncryptionMode::None {}) {
+        EncryptionMode::None {} => false,
+        _ => true,
+    };
+    match volume.fs.as_ref().unwrap_or(&FilesystemMode::Bcachefs {}) {
+        FilesystemMode::Ext4 {} | FilesystemMode::Xfs {} => {
+            unmount(log, plan, &volume_mount_path(volume)?)?;
+            if encrypted {
+                // Older versions opened volumes with a custom `uuid` as `persistent` too
+                let legacy_mapper_name = volume_name(OUTER_UUID);
+                if uuid != OUTER_UUID &&
+                    luks::is_mapped_from(&legacy_mapper_name, &PathBuf::from(format!("/dev/disk/by-uuid/{}", uuid)))? {
+                    close_luks(log, plan, &legacy_mapper_name)?;
+                }
+                close_luks(log, plan, &volume_name(uuid))?;
+            }
+            if volume.raid.is_some() {
+                let array = raid::array(uuid)?;
+                if array.dev_path.exists() {
+                    log.log(loga::INFO, format!("Stopping RAID array [{}]", array.dev_path.dbg_str()));
+                    let mut c = Command::new("mdadm");
+                    c.arg("--stop").arg(&array.dev_path);
+                    log.log(loga::DEBUG, format!("Running {:?}", c));
+                    c
+                        .simple()
+                        .apply(plan, format!("Stop RAID array {}", array.dev_path.dbg_str()))
+                        .context("Error stopping RAID array")?;
+                }
+            }
+        },
+        FilesystemMode::Bcachefs {} | FilesystemMode::Btrfs(_) => {
+            unmount(log, plan, &volume_mount_path(volume)?)?;
+        },
+        FilesystemMode::Zfs(fs_args) => {
+            unmount(log, plan, &volume_mount_path(volume)?)?;
+            let pool = fs_args.pool.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            if Command::new("zpool").arg("list").arg(pool).simple().run().is_ok() {
+                log.log(loga::INFO, format!("Exporting pool [{}]", pool));
+                let mut c = Command::new("zpool");
+                c.arg("export").arg(pool);
+                log.log(loga::DEBUG, format!("Running {:?}", c));
+                c.simple().apply(plan, format!("Export pool {}", pool)).context("Error exporting pool")?;
+            }
+        },
+        FilesystemMode::Lvm(fs_args) => {
+            let vg = fs_args.volume_group.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            for lv in fs_args.volumes.iter().rev() {
+                let mount_path =
+                    lv.mountpoint.absolutize().context("Couldn't make logical volume mountpoint absolute")?.into_owned();
+                unmount(log, plan, &mount_path)?;
+                if encrypted {
+                    close_luks(log, plan, &format!("{}-{}-crypt", vg, lv.name))?;
+                }
+            }
+            if Command::new("vgs").arg(vg).simple().run().is_ok() {
+                log.log(loga::INFO, format!("Deactivating volume group [{}]", vg));
+                let mut c = Command::new("vgchange");
+                c.arg("--activate").arg("n").arg(vg);
+                log.log(loga::DEBUG, format!("Running {:?}", c));
+                c
+                    .simple()
+                    .apply(plan, format!("Deactivate volume group {}", vg))
+                    .context("Error deactivating volume group")?;
+            }
+        },
+    }
+    return Ok(());
+}
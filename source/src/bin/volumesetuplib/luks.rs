@@ -0,0 +1,276 @@
+use {
+    super::{
+        key::KeySource,
+        plan::Plan,
+    },
+    crate::{
+        config::{
+            LuksFormatArgs,
+            LuksIntegrity,
+            LuksPbkdf,
+        },
+        util::{
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        fs::{
+            canonicalize,
+            remove_file,
+            OpenOptions,
+        },
+        io::Write,
+        os::unix::fs::OpenOptionsExt,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+/// `cryptsetup` can only read one key from stdin, so when a second key is needed
+/// it's written to a private file on tmpfs while in use.
+pub(crate) struct KeyFile {
+    pub(crate) path: PathBuf,
+    written: bool,
+}
+
+impl KeyFile {
+    pub(crate) fn new(plan: &Plan, key: &str) -> Result<Self, loga::Error> {
+        let path = PathBuf::from("/run/volumesetup_key");
+        if plan.planning() {
+            return Ok(KeyFile {
+                path: path,
+                written: false,
+            });
+        }
+        let out = KeyFile {
+            path: path.clone(),
+            written: true,
+        };
+        OpenOptions::new()
+            .mode(0o600)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .context_with("Error creating temporary key file", ea!(path = path.dbg_str()))?
+            .write_all(key.as_bytes())
+            .context_with("Error writing temporary key file", ea!(path = path.dbg_str()))?;
+        return Ok(out);
+    }
+}
+
+impl Drop for KeyFile {
+    fn drop(&mut self) {
+        if !self.written {
+            return;
+        }
+        if let Err(e) = remove_file(&self.path) {
+            eprintln!("Warning: failed to remove temporary key file [{}]: {}", self.path.dbg_str(), e);
+        }
+    }
+}
+
+/// Add the configured key derivation arguments, for commands that create
+/// keyslots.
+pub(crate) fn pbkdf_args(c: &mut Command, params: Option<&LuksFormatArgs>) -> Result<(), loga::Error> {
+    let Some(pbkdf) = params.and_then(|p| p.pbkdf.as_ref()) else {
+        return Ok(());
+    };
+    let (iterations, iter_time) = match pbkdf {
+        LuksPbkdf::Argon2id(args) => {
+            c.arg("--pbkdf=argon2id");
+            if let Some(memory) = args.memory {
+                c.arg(format!("--pbkdf-memory={}", memory));
+            }
+            if let Some(parallel) = args.parallel {
+                c.arg(format!("--pbkdf-parallel={}", parallel));
+            }
+            (args.iterations, args.iter_time)
+        },
+        LuksPbkdf::Pbkdf2(args) => {
+            c.arg("--pbkdf=pbkdf2");
+            (args.iterations, args.iter_time)
+        },
+    };
+    match (iterations, iter_time) {
+        (Some(_), Some(_)) => {
+            return Err(loga::err("The PBKDF `iterations` and `iter_time` options can't be used together"));
+        },
+        (Some(iterations), None) => {
+            c.arg(format!("--pbkdf-force-iterations={}", iterations));
+        },
+        (None, Some(iter_time)) => {
+            c.arg(format!("--iter-time={}", iter_time));
+        },
+        (None, None) => { },
+    }
+    return Ok(());
+}
+
+/// Initialize LUKS on the device with the first key, and add a keyslot for each
+/// additional key. With a detached `header` the header (and keyslots) is written
+/// there instead of to the device.
+pub(crate) fn format(
+    log: &Log,
+    plan: &Plan,
+    dev_path: &Path,
+    header: Option<&Path>,
+    params: Option<&LuksFormatArgs>,
+    keys: &[(KeySource, String)],
+) -> Result<(), loga::Error> {
+    let Some(((_, first_key), other_keys)) = keys.split_first() else {
+        return Err(loga::err("No keys to initialize LUKS device with"));
+    };
+    log.log_with(loga::INFO, "Initializing LUKS device", ea!(dev = dev_path.dbg_str(), header = header.dbg_str()));
+    let mut c = Command::new("cryptsetup");
+    c.arg("luksFormat").arg("--type=luks2");
+    if let Some(header) = header {
+        c.arg(format!("--header={}", header.to_string_lossy()));
+    }
+    let mut integrity = false;
+    if let Some(params) = params {
+        let mut cipher = params.cipher.clone();
+        match &params.integrity {
+            Some(LuksIntegrity::HmacSha256) => {
+                c.arg("--integrity=hmac-sha256");
+                integrity = true;
+            },
+            Some(LuksIntegrity::Aead) => {
+                c.arg("--integrity=aead");
+                if cipher.is_none() {
+                    cipher = Some("aes-gcm-random".to_string());
+                }
+                integrity = true;
+            },
+            None => { },
+        }
+        if let Some(cipher) = &cipher {
+            c.arg(format!("--cipher={}", cipher));
+        }
+        if let Some(key_size) = params.key_size {
+            c.arg(format!("--key-size={}", key_size));
+        }
+        if let Some(sector_size) = params.sector_size {
+            c.arg(format!("--sector-size={}", sector_size));
+        }
+        if let Some(label) = &params.label {
+            c.arg(format!("--label={}", label));
+        }
+        if let Some(subsystem) = &params.subsystem {
+            c.arg(format!("--subsystem={}", subsystem));
+        }
+    }
+    pbkdf_args(&mut c, params)?;
+    c.arg("--key-file=-").arg(dev_path);
+    if integrity {
+        // The integrity tags are initialized by wiping the whole device, report progress
+        // since it takes a while
+        log.log_with(
+            loga::INFO,
+            "Wiping device to initialize integrity tags, this may take a long time",
+            ea!(dev = dev_path.dbg_str()),
+        );
+        c.arg("--progress-frequency=30");
+        c
+            .simple()
+            .apply_stdin_lines(
+                plan,
+                format!("Initialize LUKS with integrity on {} and wipe it", dev_path.dbg_str()),
+                first_key.as_bytes(),
+                |line| log.log_with(loga::INFO, "Integrity wipe progress", ea!(dev = dev_path.dbg_str(), progress = line)),
+            )
+            .context("Error encypting new volume")?;
+    } else {
+        c
+            .simple()
+            .apply_stdin(plan, format!("Initialize LUKS on {}", dev_path.dbg_str()), first_key.as_bytes())
+            .context("Error encypting new volume")?;
+    }
+    for (_, key) in other_keys {
+        add_key(log, plan, header.unwrap_or(dev_path), params, first_key, key)?;
+    }
+    return Ok(());
+}
+
+/// Add a keyslot for `new_key`, unlocking with `key`.
+pub(crate) fn add_key(
+    log: &Log,
+    plan: &Plan,
+    dev_path: &Path,
+    params: Option<&LuksFormatArgs>,
+    key: &str,
+    new_key: &str,
+) -> Result<(), loga::Error> {
+    log.log_with(loga::INFO, "Adding LUKS keyslot", ea!(dev = dev_path.dbg_str()));
+    let new_key = KeyFile::new(plan, new_key)?;
+    let mut c = Command::new("cryptsetup");
+    c.arg("luksAddKey");
+    pbkdf_args(&mut c, params)?;
+    c.arg("--key-file=-").arg(dev_path).arg(&new_key.path);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c
+        .simple()
+        .apply_stdin(plan, format!("Add LUKS keyslot on {}", dev_path.dbg_str()), key.as_bytes())
+        .context("Error adding LUKS keyslot")?;
+    return Ok(());
+}
+
+/// Open the LUKS device as `/dev/mapper/<mapper_name>`, using the detached
+/// `header` if set.
+pub(crate) fn open(
+    log: &Log,
+    plan: &Plan,
+    dev_path: &Path,
+    header: Option<&Path>,
+    mapper_name: &str,
+    key: &str,
+) -> Result<(), loga::Error> {
+    log.log_with(loga::INFO, "Unlocking LUKS device", ea!(dev = dev_path.dbg_str(), header = header.dbg_str()));
+    let mut c = Command::new("cryptsetup");
+    c.arg("open");
+    if let Some(header) = header {
+        c.arg(format!("--header={}", header.to_string_lossy()));
+    }
+    c
+        .arg("--key-file=-")
+        .arg(dev_path)
+        .arg(mapper_name)
+        .simple()
+        .apply_stdin(plan, format!("Open LUKS device {} as {}", dev_path.dbg_str(), mapper_name), key.as_bytes())
+        .context("Error opening encrypted volume")?;
+    return Ok(());
+}
+
+/// Whether `/dev/mapper/<mapper_name>` is open and backed by `dev_path`.
+pub(crate) fn is_mapped_from(mapper_name: &str, dev_path: &Path) -> Result<bool, loga::Error> {
+    if !PathBuf::from(format!("/dev/mapper/{}", mapper_name)).exists() {
+        return Ok(false);
+    }
+    let status =
+        from_utf8(
+            Command::new("cryptsetup")
+                .arg("status")
+                .arg(mapper_name)
+                .simple()
+                .run_stdout()
+                .context_with("Error getting LUKS device status", ea!(name = mapper_name))?,
+        ).context("LUKS device status isn't valid utf-8")?;
+    let Some(device) =
+        status.lines().filter_map(|l| l.trim().strip_prefix("device:")).map(|d| PathBuf::from(d.trim())).next() else {
+            return Ok(false);
+        };
+    let Ok(dev_path) = canonicalize(dev_path) else {
+        return Ok(false);
+    };
+    return Ok(canonicalize(&device).ok() == Some(dev_path));
+}
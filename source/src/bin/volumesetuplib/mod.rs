@@ -1,5 +1,23 @@
+#[cfg(feature = "age")]
+pub mod age_key;
 pub mod blockdev;
 pub mod fs_ext4;
 pub mod fs_bcachefs;
+pub mod fs_btrfs;
+pub mod fs_lvm;
+pub mod fs_xfs;
+pub mod fs_zfs;
+pub mod header_backup;
 pub mod key;
+pub mod lock;
+pub mod luks;
+pub mod partition;
+pub mod plan;
+pub mod raid;
+pub mod recovery;
+pub mod rekey;
+pub mod shamir;
+pub mod status;
+pub mod tang;
+pub mod tpm2;
 pub mod util;
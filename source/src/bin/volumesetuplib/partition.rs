@@ -0,0 +1,185 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        plan::Plan,
+    },
+    crate::{
+        blockdev::{
+            find_selectable,
+            signatures,
+        },
+        config::{
+            DiskSelection,
+            PARTITION_TYPE_UUID,
+            PARTITION_UUID,
+            PartitionArgs,
+            WipePolicy,
+        },
+        util::{
+            derive_uuid,
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+        thread::sleep,
+        time::Duration,
+    },
+};
+
+/// Path of the partition for the volume with the given UUID.
+pub(crate) fn partition_path(volume_uuid: &str) -> Result<PathBuf, loga::Error> {
+    return Ok(PathBuf::from(format!("/dev/disk/by-partuuid/{}", derive_uuid(volume_uuid, PARTITION_UUID)?)));
+}
+
+/// Get a sector number from `sgdisk` (which may also print warnings before it).
+fn sgdisk_sector(arg: &str, disk: &Path) -> Result<u64, loga::Error> {
+    let raw =
+        from_utf8(
+            Command::new("sgdisk")
+                .arg(arg)
+                .arg(disk)
+                .simple()
+                .run_stdout()
+                .context("Error querying free space on disk")?,
+        ).context("Free space query output isn't valid utf-8")?;
+    let last = raw.trim().lines().last().unwrap_or_default().trim();
+    return Ok(
+        u64::from_str_radix(
+            last,
+            10,
+        ).context_with("Error parsing sector from free space query", ea!(output = raw, disk = disk.dbg_str()))?,
+    );
+}
+
+/// Create the volume's partition, either on the best unused disk or in free space
+/// on an in-use disk. Returns the partition path.
+pub(crate) fn create(
+    log: &Log,
+    plan: &Plan,
+    volume_uuid: &str,
+    args: &PartitionArgs,
+    unused: Vec<LsblkDevice>,
+    blocks: Vec<LsblkDevice>,
+    selection: Option<&DiskSelection>,
+) -> Result<PathBuf, loga::Error> {
+    let part_path = partition_path(volume_uuid)?;
+    if part_path.exists() {
+        // Only reuse it if it's empty, otherwise it's someone's data
+        let found = signatures(&part_path)?;
+        if !found.is_empty() {
+            match selection.and_then(|d| d.allow_wipe.as_ref()) {
+                Some(WipePolicy::Allow) => {
+                    log.log_with(
+                        loga::WARN,
+                        "Partition isn't blank, it will be overwritten",
+                        ea!(dev = part_path.dbg_str(), signatures = found.join(", ")),
+                    );
+                },
+                _ => {
+                    return Err(
+                        loga::err_with(
+                            "Partition exists but isn't blank, refusing to overwrite it. If this is a new volume set `allow_wipe` to `allow`.",
+                            ea!(dev = part_path.dbg_str(), signatures = found.join(", ")),
+                        ),
+                    );
+                },
+            }
+        }
+        log.log_with(
+            loga::INFO,
+            "Partition already exists; assuming formatting never completed.",
+            ea!(dev = part_path.dbg_str()),
+        );
+        return Ok(part_path);
+    }
+    let part_uuid = derive_uuid(volume_uuid, PARTITION_UUID)?;
+    let mut c = Command::new("sgdisk");
+    let disk;
+    if let Some(candidate) = unused.into_iter().next() {
+        log.log_with(
+            loga::INFO,
+            "Couldn't find persistent disk, partitioning best attached candidate disk",
+            ea!(disk = candidate.path.dbg_str()),
+        );
+        c.arg("--clear");
+        disk = candidate.path;
+
+        // The whole disk is used. Otherwise only the partition is claimed, so other
+        // volumes can still use the rest of the disk.
+        plan.claim(&disk);
+    } else if args.use_free_space.unwrap_or(false) {
+        let min_size = args.min_size.unwrap_or(1024 * 1024 * 1024);
+        let mut best = None;
+        for candidate in find_selectable(blocks, selection)? {
+            if candidate.pttype.as_ref().map(|x| x.as_str()) != Some("gpt") {
+                continue;
+            }
+            let first = sgdisk_sector("--first-aligned-in-largest", &candidate.path)?;
+            let last = sgdisk_sector("--end-of-largest", &candidate.path)?;
+            let free = if last > first {
+                (last - first + 1) * candidate.log_sec.unwrap_or(512)
+            } else {
+                0
+            };
+            log.log_with(
+                loga::DEBUG,
+                "Found free space on disk",
+                ea!(disk = candidate.path.dbg_str(), size = free),
+            );
+            if free < min_size {
+                continue;
+            }
+            if best.as_ref().map(|(best_free, _)| free > *best_free).unwrap_or(true) {
+                best = Some((free, candidate.path));
+            }
+        }
+        let (free, path) = best.context("Couldn't find persistent disk, unused disks, or enough free space to create a partition")?;
+        log.log_with(
+            loga::INFO,
+            "Couldn't find persistent disk, creating partition in free space of attached disk",
+            ea!(disk = path.dbg_str(), size = free),
+        );
+        disk = path;
+    } else {
+        return Err(loga::err("Couldn't find persistent disk or a suitable candidate for partitioning"));
+    }
+    c
+        .arg("--new=0:0:0")
+        .arg(format!("--typecode=0:{}", PARTITION_TYPE_UUID))
+        .arg(format!("--partition-guid=0:{}", part_uuid))
+        .arg("--change-name=0:volumesetup")
+        .arg(&disk);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().apply(plan, format!("Create partition on {}", disk.dbg_str())).context("Error creating partition")?;
+
+    // The kernel can't reread the whole partition table of an in-use disk, but can
+    // add new partitions
+    let mut c = Command::new("partx");
+    c.arg("--update").arg(&disk);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    if let Err(e) = c.simple().apply(plan, format!("Update kernel partition table for {}", disk.dbg_str())) {
+        log.log_err(loga::DEBUG, e.context("Failed to update kernel partition table, may already be up to date"));
+    }
+    if plan.planning() {
+        return Ok(part_path);
+    }
+    for _ in 0 .. 30 {
+        if part_path.exists() {
+            return Ok(part_path);
+        }
+        sleep(Duration::from_secs(1));
+    }
+    return Err(loga::err_with("Partition never appeared", ea!(path = part_path.dbg_str())));
+}
@@ -0,0 +1,106 @@
+use {
+    serde::Serialize,
+    std::{
+        cell::RefCell,
+        collections::HashSet,
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct Action {
+    /// What the action does.
+    pub(crate) description: String,
+    /// The command that would be run, if the action is a command.
+    pub(crate) command: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct VolumePlan {
+    /// Index of the volume in the config.
+    pub(crate) volume: usize,
+    pub(crate) actions: Vec<Action>,
+}
+
+/// Whether actions that modify the system are done or only recorded (`--plan`).
+/// Passed to everything that may modify the system.
+pub(crate) struct Plan {
+    /// `None` when actions are done rather than recorded.
+    actions: Option<RefCell<Vec<Action>>>,
+    /// Disks the volume will use.
+    claimed: RefCell<HashSet<PathBuf>>,
+}
+
+impl Plan {
+    /// If `record` is set, actions are recorded instead of done.
+    pub(crate) fn new(record: bool) -> Plan {
+        return Plan {
+            actions: if record {
+                Some(RefCell::new(vec![]))
+            } else {
+                None
+            },
+            claimed: RefCell::new(HashSet::new()),
+        };
+    }
+
+    pub(crate) fn planning(&self) -> bool {
+        return self.actions.is_some();
+    }
+
+    /// If planning, record the action and return `true` - the caller should skip
+    /// doing it. Otherwise returns `false`.
+    pub(crate) fn planned(&self, description: impl ToString, command: Option<&Command>) -> bool {
+        let Some(actions) = &self.actions else {
+            return false;
+        };
+        actions.borrow_mut().push(Action {
+            description: description.to_string(),
+            command: command.map(|c| {
+                let mut out = vec![c.get_program().to_string_lossy().to_string()];
+                out.extend(c.get_args().map(|a| a.to_string_lossy().to_string()));
+                out
+            }),
+        });
+        return true;
+    }
+
+    /// Record that the volume uses the disk, so later volumes don't pick it too. When
+    /// planning nothing is set up, so this is the only way to tell.
+    pub(crate) fn claim(&self, disk: &Path) {
+        self.claimed.borrow_mut().insert(disk.to_path_buf());
+    }
+
+    pub(crate) fn claimed(&self) -> HashSet<PathBuf> {
+        return self.claimed.borrow().clone();
+    }
+
+    /// The recorded actions.
+    pub(crate) fn into_actions(self) -> Vec<Action> {
+        return self.actions.map(|a| a.into_inner()).unwrap_or_default();
+    }
+}
+
+/// Human readable form of the plan.
+pub(crate) fn to_text(plans: &[VolumePlan]) -> String {
+    let mut out = String::new();
+    for plan in plans {
+        out.push_str(&format!("Volume {}:\n", plan.volume));
+        if plan.actions.is_empty() {
+            out.push_str("  Nothing to do\n");
+        }
+        for action in &plan.actions {
+            out.push_str(&format!("  - {}\n", action.description));
+            if let Some(command) = &action.command {
+                out.push_str(&format!("    $ {}\n", command.join(" ")));
+            }
+        }
+    }
+    return out;
+}
@@ -0,0 +1,190 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        plan::Plan,
+    },
+    crate::{
+        config::{
+            RAID_UUID,
+            RaidArgs,
+            RaidLevel,
+        },
+        util::{
+            derive_uuid,
+            volume_name,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        fs::{
+            canonicalize,
+            read_to_string,
+        },
+        path::PathBuf,
+        process::Command,
+        thread::sleep,
+        time::Duration,
+    },
+};
+
+pub(crate) struct Array {
+    pub(crate) uuid: String,
+    pub(crate) dev_path: PathBuf,
+}
+
+/// The array backing the volume with the given UUID.
+pub(crate) fn array(volume_uuid: &str) -> Result<Array, loga::Error> {
+    return Ok(Array {
+        uuid: derive_uuid(volume_uuid, RAID_UUID)?,
+        dev_path: PathBuf::from(format!("/dev/md/{}", volume_name(volume_uuid))),
+    });
+}
+
+fn level_arg(level: &RaidLevel) -> &'static str {
+    match level {
+        RaidLevel::Raid1 => "1",
+        RaidLevel::Raid5 => "5",
+        RaidLevel::Raid6 => "6",
+        RaidLevel::Raid10 => "10",
+    }
+}
+
+fn level_min_devices(level: &RaidLevel) -> usize {
+    match level {
+        RaidLevel::Raid1 => 2,
+        RaidLevel::Raid5 => 3,
+        RaidLevel::Raid6 => 4,
+        RaidLevel::Raid10 => 4,
+    }
+}
+
+/// Is the disk already a member of some md array?
+fn is_member(candidate: &LsblkDevice) -> bool {
+    return candidate.children.iter().any(|c| c.type_.starts_with("raid"));
+}
+
+/// Start the array if it exists but wasn't assembled automatically (ex: it's
+/// degraded).
+pub(crate) fn assemble(log: &Log, plan: &Plan, array: &Array) -> Result<(), loga::Error> {
+    if array.dev_path.exists() {
+        return Ok(());
+    }
+    let mut c = Command::new("mdadm");
+    c.arg("--assemble").arg("--scan").arg("--run").arg(format!("--uuid={}", array.uuid));
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    if let Err(e) = c.simple().apply(plan, format!("Assemble RAID array {}", array.dev_path.dbg_str())) {
+        // Also fails if there's no array yet
+        log.log_err(loga::DEBUG, e.context("Failed to assemble existing array"));
+    }
+    return Ok(());
+}
+
+/// Create a new array from the unused disks, returning the path of the array
+/// device.
+pub(crate) fn create(
+    log: &Log,
+    plan: &Plan,
+    array: &Array,
+    args: &RaidArgs,
+    unused: Vec<LsblkDevice>,
+) -> Result<PathBuf, loga::Error> {
+    let unused = unused.into_iter().filter(|b| !is_member(b)).collect::<Vec<_>>();
+    let min_devices = level_min_devices(&args.level);
+    if unused.len() < min_devices {
+        return Err(
+            loga::err_with(
+                "No existing volume found, and insufficient unused block devices to create new array with the configured level",
+                ea!(found = unused.len(), required = min_devices),
+            ),
+        );
+    }
+    log.log(loga::INFO, "Creating RAID array");
+    let mut c = Command::new("mdadm");
+    c
+        .arg("--create")
+        .arg(&array.dev_path)
+        .arg("--run")
+        .arg("--metadata=1.2")
+        .arg(format!("--uuid={}", array.uuid))
+        .arg(format!("--level={}", level_arg(&args.level)))
+        .arg(format!("--raid-devices={}", unused.len()));
+    for b in unused {
+        log.log(loga::INFO, format!("With volume [{}]", b.path.dbg_str()));
+        plan.claim(&b.path);
+        c.arg(b.path);
+    }
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c
+        .simple()
+        .apply(plan, format!("Create RAID array {}", array.dev_path.dbg_str()))
+        .context("Error creating RAID array")?;
+    if plan.planning() {
+        return Ok(array.dev_path.clone());
+    }
+    for _ in 0 .. 30 {
+        if array.dev_path.exists() {
+            return Ok(array.dev_path.clone());
+        }
+        sleep(Duration::from_secs(1));
+    }
+    return Err(loga::err_with("RAID array device never appeared", ea!(path = array.dev_path.dbg_str())));
+}
+
+/// Remove failed disks from the array and add unused disks in their place.
+pub(crate) fn repair(log: &Log, plan: &Plan, array: &Array, unused: Vec<LsblkDevice>) -> Result<(), loga::Error> {
+    if plan.planning() && !array.dev_path.exists() {
+        // Degraded state can't be checked until the array is assembled
+        return Ok(());
+    }
+    let md_path =
+        canonicalize(
+            &array.dev_path,
+        ).context_with("Error resolving RAID array device", ea!(path = array.dev_path.dbg_str()))?;
+    let md_name = md_path.file_name().context("RAID array device path has no file name")?.to_string_lossy();
+    let degraded_path = format!("/sys/block/{}/md/degraded", md_name);
+    let degraded =
+        read_to_string(
+            &degraded_path,
+        ).context_with("Error reading RAID array degraded state", ea!(path = degraded_path))?;
+    let degraded =
+        usize::from_str_radix(
+            degraded.trim(),
+            10,
+        ).context_with("Error parsing RAID array degraded count", ea!(raw = degraded))?;
+    if degraded == 0 {
+        return Ok(());
+    }
+    log.log(loga::INFO, format!("RAID array is missing {} devices, repairing", degraded));
+    for which in ["failed", "detached"] {
+        let mut c = Command::new("mdadm");
+        c.arg("--manage").arg(&array.dev_path).arg("--remove").arg(which);
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c
+            .simple()
+            .apply(plan, format!("Remove {} devices from RAID array {}", which, array.dev_path.dbg_str()))
+            .context("Error removing failed devices from RAID array")?;
+    }
+    let unused = unused.into_iter().filter(|b| !is_member(b)).take(degraded).collect::<Vec<_>>();
+    if unused.is_empty() {
+        log.log(loga::WARN, "No unused disks available to replace missing RAID array devices");
+    }
+    for b in unused {
+        log.log(loga::INFO, format!("Adding new device [{}] to RAID array", b.path.dbg_str()));
+        plan.claim(&b.path);
+        let mut c = Command::new("mdadm");
+        c.arg("--manage").arg(&array.dev_path).arg("--add").arg(&b.path);
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c
+            .simple()
+            .apply(plan, format!("Add device {} to RAID array {}", b.path.dbg_str(), array.dev_path.dbg_str()))
+            .context("Error adding new device to RAID array")?;
+    }
+    return Ok(());
+}
+
@@ -0,0 +1,217 @@
+use {
+    super::{
+        key::new_random_key,
+        plan::Plan,
+    },
+    crate::config::{
+        EscrowRecipient,
+        RecoveryArgs,
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    sequoia_openpgp::{
+        parse::Parse,
+        policy::StandardPolicy,
+        serialize::stream::{
+            Armorer,
+            Encryptor2,
+            LiteralWriter,
+            Message,
+        },
+        Cert,
+    },
+    std::{
+        fs::{
+            create_dir_all,
+            remove_file,
+            File,
+            OpenOptions,
+        },
+        io::Write,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+};
+#[cfg(feature = "age")]
+use super::age_key;
+
+fn encrypt_openpgp(cert_paths: &[&Path], data: &[u8]) -> Result<Vec<u8>, loga::Error> {
+    let mut certs = vec![];
+    for cert_path in cert_paths {
+        certs.push(
+            Cert::from_file(
+                cert_path,
+            ).map_err(
+                |e| loga::err(
+                    e.to_string(),
+                ).context_with("Error reading escrow public key", ea!(path = cert_path.dbg_str())),
+            )?,
+        );
+    }
+    let policy = StandardPolicy::new();
+    let mut recipients = vec![];
+    for (cert, cert_path) in certs.iter().zip(cert_paths) {
+        let keys =
+            cert
+                .keys()
+                .with_policy(&policy, None)
+                .supported()
+                .alive()
+                .revoked(false)
+                .for_transport_encryption()
+                .for_storage_encryption()
+                .collect::<Vec<_>>();
+        if keys.is_empty() {
+            return Err(
+                loga::err_with("Escrow public key has no valid encryption keys", ea!(path = cert_path.dbg_str())),
+            );
+        }
+        recipients.extend(keys);
+    }
+    let mut out = vec![];
+    (|| -> sequoia_openpgp::Result<()> {
+        let message = Armorer::new(Message::new(&mut out)).build()?;
+        let message = Encryptor2::for_recipients(message, recipients).build()?;
+        let mut message = LiteralWriter::new(message).build()?;
+        message.write_all(data)?;
+        message.finalize()?;
+        Ok(())
+    })().map_err(|e| loga::err(e.to_string()).context("Error encrypting to escrow public key"))?;
+    return Ok(out);
+}
+
+/// Encrypt (ASCII armored) to all the recipients, which must all be the same type.
+pub(crate) fn encrypt(recipients: &[EscrowRecipient], data: &[u8]) -> Result<Vec<u8>, loga::Error> {
+    let mut openpgp = vec![];
+    #[cfg(feature = "age")]
+    let mut age = vec![];
+    for recipient in recipients {
+        match recipient {
+            EscrowRecipient::Openpgp(cert_path) => openpgp.push(cert_path.as_path()),
+            #[cfg(feature = "age")]
+            EscrowRecipient::Age(recipient) => age.push(recipient.as_str()),
+        }
+    }
+    #[cfg(feature = "age")]
+    if !age.is_empty() {
+        if !openpgp.is_empty() {
+            return Err(loga::err("OpenPGP and age recipients can't be mixed"));
+        }
+        return Ok(age_key::encrypt(&age, data)?);
+    }
+    if openpgp.is_empty() {
+        return Err(loga::err("No escrow recipients"));
+    }
+    return Ok(encrypt_openpgp(&openpgp, data)?);
+}
+
+/// File extension for data encrypted to the recipients with `encrypt`.
+pub(crate) fn extension(recipients: &[EscrowRecipient]) -> &'static str {
+    for recipient in recipients {
+        match recipient {
+            EscrowRecipient::Openpgp(_) => { },
+            #[cfg(feature = "age")]
+            EscrowRecipient::Age(_) => return "age",
+        }
+    }
+    return "asc";
+}
+
+/// Where to write the recovery key for a logical volume: the configured path with
+/// the logical volume name added before the extension, ex: `recovery-home.asc`.
+pub(crate) fn lv_path(args: &RecoveryArgs, lv_name: &str) -> PathBuf {
+    let mut name = args.path.file_stem().unwrap_or_default().to_os_string();
+    name.push("-");
+    name.push(lv_name);
+    if let Some(ext) = args.path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    return args.path.with_file_name(name);
+}
+
+/// A recovery key file, reserved before anything destructive is done so that an
+/// existing file (which may be the only copy of another key) stops setup while it's
+/// still safe. If the key is never written the reserved file is removed again.
+pub(crate) struct Escrow<'a> {
+    args: &'a RecoveryArgs,
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// Check that the recovery key can be escrowed to `path` and create the (empty)
+/// file there. An existing file is never overwritten.
+pub(crate) fn reserve<'a>(plan: &Plan, args: &'a RecoveryArgs, path: &Path) -> Result<Escrow<'a>, loga::Error> {
+    if path.exists() {
+        return Err(
+            loga::err_with("Recovery key file already exists, refusing to overwrite it", ea!(path = path.dbg_str())),
+        );
+    }
+
+    // Make sure the recipient is usable before anything is formatted
+    encrypt(std::slice::from_ref(&args.recipient), &[]).context("Error checking escrow recipient")?;
+    let mut out = Escrow {
+        args: args,
+        path: path.to_path_buf(),
+        file: None,
+    };
+    if plan.planning() {
+        return Ok(out);
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).context_with("Error creating recovery key directory", ea!(path = parent.dbg_str()))?;
+    }
+    out.file =
+        Some(
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .context_with("Error creating recovery key file", ea!(path = path.dbg_str()))?,
+        );
+    return Ok(out);
+}
+
+impl<'a> Escrow<'a> {
+    /// Encrypt the key to the escrow recipient and write it to the reserved file.
+    pub(crate) fn write(mut self, log: &Log, plan: &Plan, key: &str) -> Result<(), loga::Error> {
+        if plan.planned(format!("Write recovery key encrypted to escrow recipient to {}", self.path.dbg_str()), None) {
+            return Ok(());
+        }
+        let encrypted = encrypt(std::slice::from_ref(&self.args.recipient), key.as_bytes())?;
+        self
+            .file
+            .as_mut()
+            .unwrap()
+            .write_all(&encrypted)
+            .context_with("Error writing recovery key", ea!(path = self.path.dbg_str()))?;
+        self.file = None;
+        log.log_with(
+            loga::INFO,
+            "Wrote encrypted recovery key, make sure it's backed up",
+            ea!(path = self.path.dbg_str()),
+        );
+        return Ok(());
+    }
+
+    /// Generate a new recovery key to enroll, and escrow it.
+    pub(crate) fn create(self, log: &Log, plan: &Plan) -> Result<String, loga::Error> {
+        let key = new_random_key();
+        self.write(log, plan, &key)?;
+        return Ok(key);
+    }
+}
+
+impl<'a> Drop for Escrow<'a> {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use {
+    super::{
+        blockdev::LsblkDevice,
+        header_backup,
+        luks::{
+            pbkdf_args,
+            KeyFile,
+        },
+        plan::Plan,
+    },
+    crate::{
+        blockdev::lsblk,
+        config::{
+            EncryptionMode,
+            FilesystemMode,
+            LuksFormatArgs,
+            OUTER_UUID,
+            Volume,
+        },
+        key::{
+            get_key,
+            key_sources,
+            with_key,
+        },
+        util::{
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        path::{
+            Path,
+            PathBuf,
+        },
+        process::Command,
+    },
+};
+
+fn rekey_luks(
+    log: &Log,
+    plan: &Plan,
+    dev_path: &Path,
+    params: Option<&LuksFormatArgs>,
+    old_key: &str,
+    new_key: &KeyFile,
+    add: bool,
+) -> Result<(), loga::Error> {
+    if !dev_path.exists() {
+        return Err(
+            loga::err_with(
+                "LUKS device not found, make sure the volume is set up (and RAID arrays/volume groups are active)",
+                ea!(dev = dev_path.dbg_str()),
+            ),
+        );
+    }
+    let mut c = Command::new("cryptsetup");
+    let description;
+    if add {
+        log.log_with(loga::INFO, "Adding LUKS keyslot", ea!(dev = dev_path.dbg_str()));
+        c.arg("luksAddKey");
+        description = format!("Add LUKS keyslot to {}", dev_path.dbg_str());
+    } else {
+        log.log_with(loga::INFO, "Replacing LUKS key", ea!(dev = dev_path.dbg_str()));
+        c.arg("luksChangeKey");
+        description = format!("Replace LUKS key of {}", dev_path.dbg_str());
+    }
+    pbkdf_args(&mut c, params)?;
+    c.arg("--key-file=-").arg(dev_path).arg(&new_key.path);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c
+        .simple()
+        .apply_stdin(plan, description, old_key.as_bytes())
+        .context("Error changing LUKS key, is the current key correct?")?;
+    return Ok(());
+}
+
+/// Add or replace the key for an existing volume, unlocking with the configured
+/// (current) key. With multiple unlock methods, each is tried until one works.
+pub(crate) fn rekey_volume(
+    log: &Log,
+    plan: &Plan,
+    volume: &Volume,
+    new_key: &str,
+    add: bool,
+) -> Result<(), loga::Error> {
+    let uuid = volume.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
+    let encryption = volume.encryption.as_ref().unwrap_or(&EncryptionMode::None {});
+    if key_sources(encryption)?.is_empty() {
+        log.log(loga::INFO, "Volume isn't encrypted, skipping");
+        return Ok(());
+    }
+    match volume.fs.as_ref().unwrap_or(&FilesystemMode::Bcachefs {}) {
+        FilesystemMode::Ext4 {} | FilesystemMode::Xfs {} => {
+            let new_key = KeyFile::new(plan, new_key)?;
+            let dev_path = match &volume.luks_detached_header {
+                // Keyslots are in the header
+                Some(detached) => detached.header.clone(),
+                None => PathBuf::from(format!("/dev/disk/by-uuid/{}", uuid)),
+            };
+            with_key(
+                log,
+                plan,
+                encryption,
+                |old_key| rekey_luks(log, plan, &dev_path, volume.luks_format.as_ref(), old_key, &new_key, add),
+            )?;
+            if let Some(backup_args) = &volume.luks_header_backup {
+                header_backup::backup(log, plan, backup_args, &dev_path, uuid)?;
+            }
+        },
+        FilesystemMode::Lvm(fs_args) => {
+            let vg = fs_args.volume_group.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            let new_key = KeyFile::new(plan, new_key)?;
+            for lv in &fs_args.volumes {
+                let log = log.fork(ea!(lv = lv.name));
+                let dev_path = PathBuf::from(format!("/dev/{}/{}", vg, lv.name));
+                with_key(
+                    &log,
+                    plan,
+                    encryption,
+                    |old_key| rekey_luks(&log, plan, &dev_path, volume.luks_format.as_ref(), old_key, &new_key, add),
+                )?;
+                if let Some(backup_args) = &volume.luks_header_backup {
+                    header_backup::backup(&log, plan, backup_args, &dev_path, &format!("{}-{}", vg, lv.name))?;
+                }
+            }
+        },
+        FilesystemMode::Bcachefs {} => {
+            let old_key = get_key(log, plan, encryption, false)?.unwrap();
+            if add {
+                return Err(loga::err("Bcachefs only supports a single passphrase, it can only be replaced"));
+            }
+            if old_key.contains('\n') || new_key.contains('\n') {
+                return Err(loga::err("Bcachefs passphrases can't contain newlines"));
+            }
+
+            // All member devices have the superblock with the encryption key
+            fn find_members(blocks: &Vec<LsblkDevice>, uuid: &str, out: &mut Vec<PathBuf>) {
+                for candidate in blocks {
+                    if candidate.uuid.as_ref().map(|u| u.as_str()) == Some(uuid) {
+                        out.push(candidate.path.clone());
+                    }
+                    find_members(&candidate.children, uuid, out);
+                }
+            }
+
+            let mut members = vec![];
+            find_members(&lsblk()?, uuid, &mut members);
+            if members.is_empty() {
+                return Err(loga::err_with("No bcachefs member devices found", ea!(uuid = uuid)));
+            }
+            log.log(loga::INFO, "Replacing bcachefs passphrase");
+            let mut c = Command::new("bcachefs");
+            c.arg("set-passphrase");
+            for m in members {
+                c.arg(m);
+            }
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c
+                .simple()
+                .run_stdin(format!("{}\n{}\n{}\n", old_key, new_key, new_key).as_bytes())
+                .context("Error changing bcachefs passphrase, is the current key correct?")?;
+        },
+        FilesystemMode::Zfs(fs_args) => {
+            if add {
+                return Err(loga::err("ZFS only supports a single passphrase, it can only be replaced"));
+            }
+            let old_key = get_key(log, plan, encryption, false)?.unwrap();
+            let pool = fs_args.pool.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            if Command::new("zpool").arg("list").arg(pool).simple().run().is_err() {
+                return Err(loga::err_with("Pool isn't imported, make sure the volume is set up", ea!(pool = pool)));
+            }
+            let key_status =
+                from_utf8(
+                    Command::new("zfs")
+                        .arg("get")
+                        .arg("-H")
+                        .arg("-o")
+                        .arg("value")
+                        .arg("keystatus")
+                        .arg(pool)
+                        .simple()
+                        .run_stdout()
+                        .context("Error getting pool key status")?,
+                ).context("Pool key status isn't valid utf-8")?;
+            if key_status.trim() != "available" {
+                let mut c = Command::new("zfs");
+                c.arg("load-key").arg(pool);
+                log.log(loga::DEBUG, format!("Running {:?}", c));
+                c.simple().run_stdin(old_key.as_bytes()).context("Error loading pool key, is the current key correct?")?;
+            }
+            log.log(loga::INFO, "Replacing pool key");
+            let mut c = Command::new("zfs");
+            c.arg("change-key").arg("-o").arg("keyformat=passphrase").arg("-o").arg("keylocation=prompt").arg(pool);
+            log.log(loga::DEBUG, format!("Running {:?}", c));
+            c.simple().run_stdin(new_key.as_bytes()).context("Error changing pool key")?;
+        },
+        FilesystemMode::Btrfs(_) => {
+            return Err(loga::err("Btrfs doesn't support encryption"));
+        },
+    }
+    return Ok(());
+}
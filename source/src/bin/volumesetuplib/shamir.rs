@@ -0,0 +1,227 @@
+use {
+    crate::util::from_utf8,
+    loga::{
+        ea,
+        ResultContext,
+    },
+    rand::{
+        thread_rng,
+        RngCore,
+    },
+    std::collections::HashSet,
+};
+
+const SHARE_PREFIX: &str = "volumesetup-share-v1";
+
+/// Multiplication in GF(2^8) with the AES polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut out = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            out ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    return out;
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 = a^-1
+    let mut out = 1u8;
+    for _ in 0 .. 254 {
+        out = gf_mul(out, a);
+    }
+    return out;
+}
+
+/// Shamir secret sharing - split the secret into `count` shares, any `threshold`
+/// of which can be combined to recover it. Each share is a single line of text.
+pub(crate) fn split(secret: &str, threshold: usize, count: usize) -> Result<Vec<String>, loga::Error> {
+    if threshold < 1 || threshold > count || count > 255 {
+        return Err(
+            loga::err_with(
+                "Threshold must be between 1 and the share count, and there can be at most 255 shares",
+                ea!(threshold = threshold, count = count),
+            ),
+        );
+    }
+    let mut ys = vec![vec![]; count];
+    for byte in secret.as_bytes() {
+        let mut coefficients = vec![0u8; threshold];
+        coefficients[0] = *byte;
+        thread_rng().fill_bytes(&mut coefficients[1..]);
+        for (i, y) in ys.iter_mut().enumerate() {
+            let x = (i + 1) as u8;
+
+            // Horner's method
+            let mut value = 0u8;
+            for c in coefficients.iter().rev() {
+                value = gf_mul(value, x) ^ c;
+            }
+            y.push(value);
+        }
+    }
+    return Ok(
+        ys
+            .into_iter()
+            .enumerate()
+            .map(
+                |(i, y)| format!(
+                    "{}:{}:{}:{}",
+                    SHARE_PREFIX,
+                    threshold,
+                    i + 1,
+                    y.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+                ),
+            )
+            .collect(),
+    );
+}
+
+struct Share {
+    threshold: usize,
+    x: u8,
+    y: Vec<u8>,
+}
+
+fn parse_share(share: &str) -> Result<Share, loga::Error> {
+    let parts = share.trim().split(':').collect::<Vec<_>>();
+    let [prefix, threshold, x, y] = parts.as_slice() else {
+        return Err(loga::err("Key share has the wrong number of fields"));
+    };
+    if *prefix != SHARE_PREFIX {
+        return Err(loga::err_with("Key share has unknown format", ea!(prefix = prefix)));
+    }
+    let threshold = usize::from_str_radix(threshold, 10).context("Key share threshold isn't a valid number")?;
+    if threshold == 0 {
+        return Err(loga::err("Key share threshold is 0"));
+    }
+
+    // Sliced by byte below
+    if !y.is_ascii() {
+        return Err(loga::err("Key share data isn't valid hex"));
+    }
+    if y.len() % 2 != 0 {
+        return Err(loga::err("Key share data has odd length"));
+    }
+    let mut y_bytes = vec![];
+    for i in (0 .. y.len()).step_by(2) {
+        y_bytes.push(u8::from_str_radix(&y[i .. i + 2], 16).context("Key share data isn't valid hex")?);
+    }
+    return Ok(Share {
+        threshold: threshold,
+        x: u8::from_str_radix(x, 10).context("Key share index isn't a valid number")?,
+        y: y_bytes,
+    });
+}
+
+/// The threshold recorded in a share.
+pub(crate) fn share_threshold(share: &str) -> Result<usize, loga::Error> {
+    return Ok(parse_share(share)?.threshold);
+}
+
+/// Recover the secret from at least `threshold` distinct shares.
+pub(crate) fn combine(shares: &[String]) -> Result<String, loga::Error> {
+    let shares = shares.iter().map(|s| parse_share(s)).collect::<Result<Vec<_>, _>>()?;
+    let Some(first) = shares.first() else {
+        return Err(loga::err("No key shares to combine"));
+    };
+    let threshold = first.threshold;
+    let mut seen = HashSet::new();
+    for share in &shares {
+        if share.threshold != threshold || share.y.len() != first.y.len() {
+            return Err(loga::err("Key shares are from different splits"));
+        }
+        if share.x == 0 || !seen.insert(share.x) {
+            return Err(loga::err_with("Key share index is invalid or duplicated", ea!(index = share.x)));
+        }
+    }
+    if shares.len() < threshold {
+        return Err(loga::err_with("Not enough key shares", ea!(have = shares.len(), need = threshold)));
+    }
+    let shares = &shares[..threshold];
+
+    // Lagrange interpolation at x = 0 (subtraction is xor)
+    let mut secret = vec![0u8; first.y.len()];
+    for (j, share) in shares.iter().enumerate() {
+        let mut basis = 1u8;
+        for (m, other) in shares.iter().enumerate() {
+            if m == j {
+                continue;
+            }
+            basis = gf_mul(basis, gf_mul(other.x, gf_inv(other.x ^ share.x)));
+        }
+        for (i, y) in share.y.iter().enumerate() {
+            secret[i] ^= gf_mul(*y, basis);
+        }
+    }
+    return Ok(from_utf8(secret).context("Combined key isn't valid utf-8, the shares may be corrupt")?);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        combine,
+        split,
+    };
+
+    #[test]
+    fn round_trip() {
+        let secret = "correct horse battery staple";
+        for (threshold, count) in [(1, 1), (1, 3), (2, 3), (3, 3), (3, 5), (5, 8)] {
+            let shares = split(secret, threshold, count).unwrap();
+            assert_eq!(shares.len(), count);
+
+            // Any `threshold` shares work, not just the first ones
+            assert_eq!(combine(&shares[..threshold]).unwrap(), secret);
+            assert_eq!(combine(&shares[count - threshold..]).unwrap(), secret);
+            assert_eq!(combine(&shares).unwrap(), secret);
+        }
+    }
+
+    #[test]
+    fn too_few_shares() {
+        let shares = split("secret", 3, 5).unwrap();
+        assert!(combine(&shares[..2]).is_err());
+        assert!(combine(&[]).is_err());
+    }
+
+    #[test]
+    fn duplicate_shares() {
+        let shares = split("secret", 2, 3).unwrap();
+        assert!(combine(&[shares[0].clone(), shares[0].clone()]).is_err());
+    }
+
+    #[test]
+    fn mismatched_shares() {
+        let a = split("secret", 2, 3).unwrap();
+        let b = split("secret", 3, 3).unwrap();
+        let c = split("longer secret", 2, 3).unwrap();
+        assert!(combine(&[a[0].clone(), b[1].clone()]).is_err());
+        assert!(combine(&[a[0].clone(), c[1].clone()]).is_err());
+    }
+
+    #[test]
+    fn invalid_shares() {
+        assert!(combine(&["not a share".to_string()]).is_err());
+
+        // Index 0 would be the secret itself
+        assert!(
+            combine(&["volumesetup-share-v1:2:0:00".to_string(), "volumesetup-share-v1:2:1:00".to_string()]).is_err()
+        );
+    }
+
+    #[test]
+    fn malformed_shares() {
+        // Non-ASCII data must not panic on a char boundary
+        assert!(combine(&["volumesetup-share-v1:1:1:0é0".to_string()]).is_err());
+
+        // A threshold of 0 would recover an empty key
+        assert!(combine(&["volumesetup-share-v1:0:1:00".to_string()]).is_err());
+    }
+}
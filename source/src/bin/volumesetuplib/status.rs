@@ -0,0 +1,183 @@
+use {
+    super::luks,
+    crate::{
+        blockdev::volume_disks,
+        config::{
+            EncryptionMode,
+            FilesystemMode,
+            OUTER_UUID,
+            Volume,
+        },
+        util::{
+            is_mounted,
+            volume_mount_path,
+            volume_name,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        ResultContext,
+    },
+    path_absolutize::Absolutize,
+    serde::Serialize,
+    std::{
+        fs::{
+            read_dir,
+            read_link,
+            read_to_string,
+        },
+        path::PathBuf,
+        process::Command,
+    },
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct BcachefsDeviceStatus {
+    /// Index of the device within the filesystem.
+    pub(crate) index: usize,
+    pub(crate) label: Option<String>,
+    /// `rw`, `ro`, `failed` or `spare`.
+    pub(crate) state: Option<String>,
+    /// The block device, if it's present.
+    pub(crate) device: Option<PathBuf>,
+    pub(crate) missing: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct LogicalVolumeStatus {
+    pub(crate) name: String,
+    pub(crate) exists: bool,
+    /// Whether the LUKS device is open, if encrypted.
+    pub(crate) luks_open: Option<bool>,
+    pub(crate) mountpoint: PathBuf,
+    pub(crate) mounted: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct VolumeStatus {
+    /// Index of the volume in the config.
+    pub(crate) volume: usize,
+    pub(crate) exists: bool,
+    /// Physical disks backing the volume.
+    pub(crate) disks: Vec<PathBuf>,
+    /// Whether the LUKS device is open, if encrypted with LUKS (`ext4`, `xfs`).
+    pub(crate) luks_open: Option<bool>,
+    /// Not set for `lvm`, see the logical volumes instead.
+    pub(crate) mountpoint: Option<PathBuf>,
+    /// For `lvm`, whether all logical volumes are mounted.
+    pub(crate) mounted: bool,
+    /// Member devices, only available while mounted.
+    pub(crate) bcachefs_devices: Option<Vec<BcachefsDeviceStatus>>,
+    pub(crate) logical_volumes: Option<Vec<LogicalVolumeStatus>>,
+}
+
+fn bcachefs_devices(uuid: &str) -> Result<Option<Vec<BcachefsDeviceStatus>>, loga::Error> {
+    let sys_path = PathBuf::from(format!("/sys/fs/bcachefs/{}", uuid));
+    if !sys_path.exists() {
+        return Ok(None);
+    }
+    let mut out = vec![];
+    for d in read_dir(&sys_path).context("Error reading bcachefs sys dir")? {
+        let d = d.context_with("Error reading sysfs directory entry", ea!(path = sys_path.dbg_str()))?;
+        let name = d.file_name().to_string_lossy().to_string();
+        let Some(index) = name.strip_prefix("dev-") else {
+            continue;
+        };
+        let index =
+            usize::from_str_radix(
+                index,
+                10,
+            ).context_with("Error parsing device index from sysfs tree", ea!(name = name))?;
+        let read_attr = |attr: &str| read_to_string(d.path().join(attr)).ok().map(|x| x.trim().to_string());
+        let device = match read_link(d.path().join("block")) {
+            Ok(p) => Some(PathBuf::from("/dev").join(p.file_name().context("Bcachefs dev link has no file name")?)),
+            Err(_) => None,
+        };
+        out.push(BcachefsDeviceStatus {
+            index: index,
+            label: read_attr("label"),
+            state: read_attr("state"),
+            missing: device.is_none(),
+            device: device,
+        });
+    }
+    out.sort_by_key(|d| d.index);
+    return Ok(Some(out));
+}
+
+pub(crate) fn volume_status(index: usize, volume: &Volume) -> Result<VolumeStatus, loga::Error> {
+    let uuid = volume.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID);
+    let encrypted = match volume.encryption.as_ref().unwrap_or(&EncryptionMode::None {}) {
+        EncryptionMode::None {} => false,
+        _ => true,
+    };
+    let mut disks = volume_disks(volume)?.into_iter().collect::<Vec<_>>();
+    disks.sort();
+    let mut out = VolumeStatus {
+        volume: index,
+        exists: !disks.is_empty(),
+        disks: disks,
+        luks_open: None,
+        mountpoint: None,
+        mounted: false,
+        bcachefs_devices: None,
+        logical_volumes: None,
+    };
+    match volume.fs.as_ref().unwrap_or(&FilesystemMode::Bcachefs {}) {
+        FilesystemMode::Lvm(fs_args) => {
+            let vg = fs_args.volume_group.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            out.exists = Command::new("vgs").arg(vg).simple().run().is_ok();
+            let mut lvs = vec![];
+            for lv in &fs_args.volumes {
+                let mount_path =
+                    lv.mountpoint.absolutize().context("Couldn't make logical volume mountpoint absolute")?.into_owned();
+                let mounted = is_mounted(&mount_path);
+                lvs.push(LogicalVolumeStatus {
+                    name: lv.name.clone(),
+                    exists: out.exists &&
+                        Command::new("lvs").arg(format!("{}/{}", vg, lv.name)).simple().run().is_ok(),
+                    luks_open: match encrypted {
+                        true => Some(PathBuf::from(format!("/dev/mapper/{}-{}-crypt", vg, lv.name)).exists()),
+                        false => None,
+                    },
+                    mountpoint: mount_path,
+                    mounted: mounted,
+                });
+            }
+            out.mounted = lvs.iter().all(|lv| lv.mounted);
+            out.logical_volumes = Some(lvs);
+            return Ok(out);
+        },
+        FilesystemMode::Ext4 {} | FilesystemMode::Xfs {} => {
+            if encrypted {
+                // Older versions opened volumes with a custom `uuid` as `persistent` too
+                out.luks_open =
+                    Some(
+                        PathBuf::from(format!("/dev/mapper/{}", volume_name(uuid))).exists() ||
+                            (uuid != OUTER_UUID &&
+                                luks::is_mapped_from(
+                                    &volume_name(OUTER_UUID),
+                                    &PathBuf::from(format!("/dev/disk/by-uuid/{}", uuid)),
+                                )?),
+                    );
+            }
+        },
+        FilesystemMode::Bcachefs {} => {
+            out.bcachefs_devices = bcachefs_devices(uuid)?;
+        },
+        FilesystemMode::Btrfs(_) => { },
+        FilesystemMode::Zfs(fs_args) => {
+            let pool = fs_args.pool.as_ref().map(|x| x.as_str()).unwrap_or("persistent");
+            out.exists = out.exists || Command::new("zpool").arg("list").arg(pool).simple().run().is_ok();
+        },
+    }
+    let mount_path = volume_mount_path(volume)?;
+    out.mounted = is_mounted(&mount_path);
+    out.mountpoint = Some(mount_path);
+    return Ok(out);
+}
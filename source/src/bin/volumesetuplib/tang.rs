@@ -0,0 +1,202 @@
+use {
+    super::{
+        key::new_random_key,
+        plan::Plan,
+    },
+    crate::{
+        config::TangKeyArgs,
+        util::{
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    serde_json::json,
+    std::{
+        fs::{
+            create_dir_all,
+            read,
+            write,
+        },
+        process::Command,
+    },
+};
+
+/// Get the key encrypted with the Tang servers. If there's no encrypted key yet and
+/// `init` is set (the volume is being initialized), encrypt a new random key.
+pub(crate) fn get_tang_key(log: &Log, plan: &Plan, args: &TangKeyArgs, init: bool) -> Result<String, loga::Error> {
+    let threshold = args.threshold.unwrap_or(1);
+    if threshold < 1 || threshold > args.servers.len() {
+        return Err(
+            loga::err_with(
+                "Tang threshold must be between 1 and the number of servers",
+                ea!(threshold = threshold, servers = args.servers.len()),
+            ),
+        );
+    }
+    let exists = args.jwe_path.exists();
+    if plan.planning() {
+        if !exists && init {
+            plan.planned(
+                format!(
+                    "Encrypt new key with {} of {} Tang servers to {}",
+                    threshold,
+                    args.servers.len(),
+                    args.jwe_path.dbg_str()
+                ),
+                None,
+            );
+        }
+
+        // Nothing is encrypted or unlocked when planning
+        return Ok(String::new());
+    }
+    if exists {
+        let jwe = read(&args.jwe_path).context_with("Error reading Tang JWE", ea!(path = args.jwe_path.dbg_str()))?;
+        let mut c = Command::new("clevis");
+        c.arg("decrypt");
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        let key =
+            c
+                .simple()
+                .run_stdin_stdout(&jwe)
+                .context_with(
+                    "Error decrypting key with Tang, are enough servers reachable?",
+                    ea!(threshold = threshold),
+                )?;
+        return Ok(from_utf8(key).context("Decrypted key isn't valid utf-8")?);
+    }
+    if !init {
+        return Err(
+            loga::err_with(
+                "No Tang JWE found, a key is only encrypted when initializing a volume",
+                ea!(path = args.jwe_path.dbg_str()),
+            ),
+        );
+    }
+    log.log_with(
+        loga::INFO,
+        "Encrypting new key with Tang",
+        ea!(path = args.jwe_path.dbg_str(), threshold = threshold, servers = args.servers.len()),
+    );
+    let mut pins = vec![];
+    for server in &args.servers {
+        let adv =
+            read(
+                &server.adv,
+            ).context_with("Error reading Tang advertisement", ea!(url = server.url, path = server.adv.dbg_str()))?;
+        let adv =
+            serde_json::from_slice::<serde_json::Value>(
+                &adv,
+            ).context_with("Tang advertisement isn't valid JSON", ea!(path = server.adv.dbg_str()))?;
+        pins.push(json!({
+            "url": server.url,
+            "adv": adv,
+        }));
+    }
+    let key = new_random_key();
+    let mut c = Command::new("clevis");
+    c.arg("encrypt").arg("sss").arg(json!({
+        "t": threshold,
+        "pins": {
+            "tang": pins
+        },
+    }).to_string());
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    let jwe = c.simple().run_stdin_stdout(key.as_bytes()).context("Error encrypting key with Tang")?;
+    if let Some(parent) = args.jwe_path.parent() {
+        create_dir_all(parent).context_with("Error creating Tang JWE directory", ea!(path = parent.dbg_str()))?;
+    }
+    write(&args.jwe_path, jwe).context_with("Error writing Tang JWE", ea!(path = args.jwe_path.dbg_str()))?;
+    return Ok(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::get_tang_key,
+        crate::{
+            config::{
+                TangKeyArgs,
+                TangServer,
+            },
+            plan::Plan,
+            util::SimpleCommandExt,
+        },
+        loga::Log,
+        std::{
+            fs::{
+                create_dir_all,
+                remove_dir_all,
+                write,
+            },
+            process::{
+                Child,
+                Command,
+            },
+            thread::sleep,
+            time::Duration,
+        },
+    };
+
+    struct Tangd(Child);
+
+    impl Drop for Tangd {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Needs `tang` (`tangd` and `tangd-keygen` in `/usr/libexec`), `clevis`, `socat`
+    /// and `curl` installed. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn encrypt_decrypt_tangd() {
+        let dir = std::env::temp_dir().join(format!("volumesetup_test_tang_{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        let db_dir = dir.join("db");
+        create_dir_all(&db_dir).unwrap();
+        Command::new("/usr/libexec/tangd-keygen").arg(&db_dir).simple().run().unwrap();
+        let port = 23220;
+        let tangd =
+            Tangd(
+                Command::new("socat")
+                    .arg(format!("tcp-listen:{},reuseaddr,fork", port))
+                    .arg(format!("exec:/usr/libexec/tangd {}", db_dir.to_string_lossy()))
+                    .spawn()
+                    .unwrap(),
+            );
+        sleep(Duration::from_secs(1));
+        let url = format!("http://localhost:{}", port);
+        let adv_path = dir.join("adv.json");
+        write(&adv_path, Command::new("curl").arg("-sf").arg(format!("{}/adv", url)).simple().run_stdout().unwrap())
+            .unwrap();
+        let args = TangKeyArgs {
+            jwe_path: dir.join("key.jwe"),
+            servers: vec![TangServer {
+                url,
+                adv: adv_path,
+            }],
+            threshold: None,
+        };
+        let log = Log::new_root(loga::DEBUG);
+        let plan = Plan::new(false);
+
+        // Only encrypted when initializing
+        assert!(get_tang_key(&log, &plan, &args, false).is_err());
+        let key = get_tang_key(&log, &plan, &args, true).unwrap();
+        assert_eq!(get_tang_key(&log, &plan, &args, false).unwrap(), key);
+        assert_eq!(get_tang_key(&log, &plan, &args, true).unwrap(), key);
+
+        // Can't decrypt when the server is unreachable
+        drop(tangd);
+        assert!(get_tang_key(&log, &plan, &args, false).is_err());
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,225 @@
+use {
+    super::{
+        key::new_random_key,
+        plan::Plan,
+    },
+    crate::{
+        config::Tpm2KeyArgs,
+        util::{
+            from_utf8,
+            SimpleCommandExt,
+        },
+    },
+    loga::{
+        ea,
+        DebugDisplay,
+        Log,
+        ResultContext,
+    },
+    std::{
+        fs::{
+            create_dir_all,
+            remove_dir_all,
+        },
+        path::PathBuf,
+        process::Command,
+    },
+};
+
+/// Scratch directory for TPM object contexts, removed when done.
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    fn new() -> Result<Self, loga::Error> {
+        let path = PathBuf::from("/run/volumesetup_tpm2");
+        create_dir_all(&path).context_with("Error creating TPM2 work directory", ea!(path = path.dbg_str()))?;
+        return Ok(WorkDir(path));
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = remove_dir_all(&self.0) {
+            eprintln!("Warning: failed to remove TPM2 work directory [{}]: {}", self.0.dbg_str(), e);
+        }
+    }
+}
+
+fn tpm2_command(args: &Tpm2KeyArgs, program: &str) -> Command {
+    let mut c = Command::new(program);
+    if let Some(tcti) = &args.tcti {
+        c.arg(format!("--tcti={}", tcti));
+    }
+    return c;
+}
+
+/// Get the key sealed to the TPM. If there's no sealed key yet and `init` is set
+/// (the volume is being initialized), seal a new random key.
+pub(crate) fn get_tpm2_key(log: &Log, plan: &Plan, args: &Tpm2KeyArgs, init: bool) -> Result<String, loga::Error> {
+    let pub_path = args.sealed_path.join("key.pub");
+    let priv_path = args.sealed_path.join("key.priv");
+    let sealed = pub_path.exists() && priv_path.exists();
+    let pcrs =
+        format!(
+            "{}:{}",
+            args.pcr_bank.as_ref().map(|x| x.as_str()).unwrap_or("sha256"),
+            args.pcrs.as_ref().unwrap_or(&vec![7]).iter().map(|p| p.to_string()).collect::<Vec<_>>().join(",")
+        );
+    if plan.planning() {
+        if !sealed && init {
+            plan.planned(format!("Seal new key to TPM2 in {} (PCRs {})", args.sealed_path.dbg_str(), pcrs), None);
+        }
+
+        // Nothing is encrypted or unlocked when planning
+        return Ok(String::new());
+    }
+    if !sealed && !init {
+        return Err(
+            loga::err_with(
+                "No sealed key found, a key is only sealed when initializing a volume",
+                ea!(path = args.sealed_path.dbg_str()),
+            ),
+        );
+    }
+    let work = WorkDir::new()?;
+
+    // The primary key is derived from the owner hierarchy seed, so it's the same
+    // each time it's created with the same template
+    let primary_path = work.0.join("primary.ctx");
+    let mut c = tpm2_command(args, "tpm2_createprimary");
+    c.arg("-C").arg("o").arg("-g").arg("sha256").arg("-G").arg("ecc").arg("-c").arg(&primary_path);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().run().context("Error creating TPM2 primary key")?;
+    if !sealed {
+        log.log_with(loga::INFO, "Sealing new key to TPM2", ea!(path = args.sealed_path.dbg_str(), pcrs = pcrs));
+        create_dir_all(
+            &args.sealed_path,
+        ).context_with("Error creating sealed key directory", ea!(path = args.sealed_path.dbg_str()))?;
+        let policy_path = work.0.join("policy.digest");
+        let mut c = tpm2_command(args, "tpm2_createpolicy");
+        c.arg("--policy-pcr").arg("-l").arg(&pcrs).arg("-L").arg(&policy_path);
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c.simple().run().context("Error creating TPM2 PCR policy")?;
+        let key = new_random_key();
+        let mut c = tpm2_command(args, "tpm2_create");
+        c
+            .arg("-C")
+            .arg(&primary_path)
+            .arg("-L")
+            .arg(&policy_path)
+            .arg("-i")
+            .arg("-")
+            .arg("-u")
+            .arg(&pub_path)
+            .arg("-r")
+            .arg(&priv_path);
+        log.log(loga::DEBUG, format!("Running {:?}", c));
+        c.simple().run_stdin(key.as_bytes()).context("Error sealing key to TPM2")?;
+        return Ok(key);
+    }
+    let key_path = work.0.join("key.ctx");
+    let mut c = tpm2_command(args, "tpm2_load");
+    c.arg("-C").arg(&primary_path).arg("-u").arg(&pub_path).arg("-r").arg(&priv_path).arg("-c").arg(&key_path);
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    c.simple().run().context_with("Error loading sealed key into TPM2", ea!(path = args.sealed_path.dbg_str()))?;
+    let mut c = tpm2_command(args, "tpm2_unseal");
+    c.arg("-c").arg(&key_path).arg("-p").arg(format!("pcr:{}", pcrs));
+    log.log(loga::DEBUG, format!("Running {:?}", c));
+    let key =
+        c
+            .simple()
+            .run_stdout()
+            .context_with(
+                "Error unsealing key from TPM2, PCR values may have changed since sealing (ex: firmware or boot configuration update)",
+                ea!(pcrs = pcrs),
+            )?;
+    return Ok(from_utf8(key).context("Unsealed key isn't valid utf-8")?);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::get_tpm2_key,
+        crate::{
+            config::Tpm2KeyArgs,
+            plan::Plan,
+            util::SimpleCommandExt,
+        },
+        loga::Log,
+        std::{
+            fs::{
+                create_dir_all,
+                remove_dir_all,
+            },
+            process::{
+                Child,
+                Command,
+            },
+            thread::sleep,
+            time::Duration,
+        },
+    };
+
+    struct Swtpm(Child);
+
+    impl Drop for Swtpm {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Needs `swtpm` and `tpm2-tools` installed, and write access to `/run` for the
+    /// TPM2 work directory. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn seal_unseal_swtpm() {
+        let dir = std::env::temp_dir().join(format!("volumesetup_test_tpm2_{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        let state_dir = dir.join("state");
+        create_dir_all(&state_dir).unwrap();
+        let port = 23210;
+        let _swtpm =
+            Swtpm(
+                Command::new("swtpm")
+                    .arg("socket")
+                    .arg("--tpm2")
+                    .arg("--tpmstate")
+                    .arg(format!("dir={}", state_dir.to_string_lossy()))
+                    .arg("--server")
+                    .arg(format!("type=tcp,port={}", port))
+                    .arg("--ctrl")
+                    .arg(format!("type=tcp,port={}", port + 1))
+                    .arg("--flags")
+                    .arg("not-need-init,startup-clear")
+                    .spawn()
+                    .unwrap(),
+            );
+        sleep(Duration::from_secs(1));
+        let tcti = format!("swtpm:port={}", port);
+        let args = Tpm2KeyArgs {
+            sealed_path: dir.join("sealed"),
+            pcrs: None,
+            pcr_bank: None,
+            tcti: Some(tcti.clone()),
+        };
+        let log = Log::new_root(loga::DEBUG);
+        let plan = Plan::new(false);
+
+        // Only sealed when initializing
+        assert!(get_tpm2_key(&log, &plan, &args, false).is_err());
+        let key = get_tpm2_key(&log, &plan, &args, true).unwrap();
+        assert_eq!(get_tpm2_key(&log, &plan, &args, false).unwrap(), key);
+        assert_eq!(get_tpm2_key(&log, &plan, &args, true).unwrap(), key);
+
+        // Changing a bound PCR prevents unsealing
+        Command::new("tpm2_pcrextend")
+            .arg(format!("--tcti={}", tcti))
+            .arg(format!("7:sha256={}", "00".repeat(32)))
+            .simple()
+            .run()
+            .unwrap();
+        assert!(get_tpm2_key(&log, &plan, &args, false).is_err());
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -1,16 +1,32 @@
 use {
+    super::plan::Plan,
+    crate::config::{
+        OUTER_UUID,
+        Volume,
+    },
     loga::{
         ea,
         DebugDisplay,
         Log,
         ResultContext,
     },
+    path_absolutize::Absolutize,
     serde::de::DeserializeOwned,
     std::{
-        io::Write,
+        io::{
+            BufRead,
+            BufReader,
+            Read,
+            Write,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
         process::{
             Command,
         },
+        thread::spawn,
     },
 };
 
@@ -27,6 +43,62 @@ pub(crate) fn from_utf8(data: Vec<u8>) -> Result<String, loga::Error> {
     );
 }
 
+/// Derive the UUID of a volume component (ex: the filesystem inside LUKS) from the
+/// volume UUID, so multiple volumes don't collide. For the default volume UUID this
+/// is the component's default UUID.
+pub(crate) fn derive_uuid(volume_uuid: &str, default_uuid: &str) -> Result<String, loga::Error> {
+    fn nibbles(uuid: &str) -> Result<Vec<u8>, loga::Error> {
+        let out =
+            uuid
+                .chars()
+                .filter(|c| *c != '-')
+                .map(|c| c.to_digit(16).map(|d| d as u8))
+                .collect::<Option<Vec<_>>>()
+                .context_with("UUID contains non-hex characters", ea!(uuid = uuid))?;
+        if out.len() != 32 {
+            return Err(loga::err_with("UUID has wrong length", ea!(uuid = uuid)));
+        }
+        return Ok(out);
+    }
+
+    let mut out = String::new();
+    for (i, ((a, b), c)) in Iterator::zip(
+        Iterator::zip(nibbles(volume_uuid)?.into_iter(), nibbles(OUTER_UUID)?.into_iter()),
+        nibbles(default_uuid)?.into_iter(),
+    ).enumerate() {
+        if [8, 12, 16, 20].contains(&i) {
+            out.push('-');
+        }
+        out.push(char::from_digit((a ^ b ^ c) as u32, 16).unwrap());
+    }
+    return Ok(out);
+}
+
+/// Name to use for device mapper devices, md arrays, etc. for a volume.
+pub(crate) fn volume_name(volume_uuid: &str) -> String {
+    if volume_uuid == OUTER_UUID {
+        return "persistent".to_string();
+    }
+    return format!("persistent-{}", volume_uuid.chars().take(8).collect::<String>());
+}
+
+/// Where the volume is mounted (not used with `lvm`).
+pub(crate) fn volume_mount_path(volume: &Volume) -> Result<PathBuf, loga::Error> {
+    return Ok(
+        volume
+            .mountpoint
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/mnt/persistent"))
+            .absolutize()
+            .context("Couldn't make mountpoint absolute")?
+            .into_owned(),
+    );
+}
+
+pub(crate) fn is_mounted(mount_path: &Path) -> bool {
+    return Command::new("findmnt").arg("--mountpoint").arg(mount_path).simple().run().is_ok();
+}
+
 pub(crate) struct SimpleCommand<'a>(&'a mut Command);
 
 impl<'a> SimpleCommand<'a> {
@@ -66,6 +138,93 @@ impl<'a> SimpleCommand<'a> {
         return Ok(());
     }
 
+    /// Run a command that modifies the system. When planning, the command is
+    /// recorded with the description instead.
+    pub(crate) fn apply(&mut self, plan: &Plan, description: impl ToString) -> Result<(), loga::Error> {
+        if plan.planned(description, Some(self.0)) {
+            return Ok(());
+        }
+        return self.run();
+    }
+
+    /// Like `apply`, with data piped to stdin.
+    pub(crate) fn apply_stdin(
+        &mut self,
+        plan: &Plan,
+        description: impl ToString,
+        data: &[u8],
+    ) -> Result<(), loga::Error> {
+        if plan.planned(description, Some(self.0)) {
+            return Ok(());
+        }
+        return self.run_stdin(data);
+    }
+
+    /// Like `apply_stdin`, but calls `on_line` with each line of output as it's
+    /// written, for long running commands that report progress.
+    pub(crate) fn apply_stdin_lines(
+        &mut self,
+        plan: &Plan,
+        description: impl ToString,
+        data: &[u8],
+        mut on_line: impl FnMut(&str),
+    ) -> Result<(), loga::Error> {
+        if plan.planned(description, Some(self.0)) {
+            return Ok(());
+        }
+        let log = Log::new().fork(ea!(command = self.0.dbg_str()));
+        self.0.stdout(std::process::Stdio::piped());
+        self.0.stderr(std::process::Stdio::piped());
+        self.0.stdin(std::process::Stdio::piped());
+        let mut child = self.0.spawn().stack_context(&log, "Failed to start child process")?;
+
+        // Close stdin so the child doesn't wait for more
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(data)
+            .stack_context(&log, "Error writing to child process stdin")?;
+
+        // Read stderr concurrently so the child doesn't block if it fills the pipe
+        let mut stderr_pipe = child.stderr.take().unwrap();
+        let stderr_thread = spawn(move || {
+            let mut stderr = vec![];
+            let _ = stderr_pipe.read_to_end(&mut stderr);
+            return stderr;
+        });
+        let mut stdout = vec![];
+        let mut reader = BufReader::new(child.stdout.take().unwrap());
+        loop {
+            let mut line = vec![];
+            let count =
+                reader.read_until(b'\n', &mut line).stack_context(&log, "Error reading child process output")?;
+            if count == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            if !text.trim().is_empty() {
+                on_line(text.trim());
+            }
+            stdout.extend(line);
+        }
+        let status = child.wait().stack_context(&log, "Failed to wait for child process to exit")?;
+        let stderr = stderr_thread.join().unwrap_or_default();
+        if !status.success() {
+            return Err(
+                log.err_with(
+                    "Child process exited with error",
+                    ea!(
+                        code = status.code().dbg_str(),
+                        stdout = String::from_utf8_lossy(&stdout),
+                        stderr = String::from_utf8_lossy(&stderr)
+                    ),
+                ),
+            );
+        }
+        return Ok(());
+    }
+
     pub(crate) fn run_stdout(&mut self) -> Result<Vec<u8>, loga::Error> {
         let log = Log::new().fork(ea!(command = self.0.dbg_str()));
         self.0.stdout(std::process::Stdio::piped());
@@ -83,6 +242,26 @@ impl<'a> SimpleCommand<'a> {
         return Ok(output.stdout);
     }
 
+    pub(crate) fn run_stdin_stdout(&mut self, data: &[u8]) -> Result<Vec<u8>, loga::Error> {
+        let log = Log::new().fork(ea!(command = self.0.dbg_str()));
+        self.0.stdout(std::process::Stdio::piped());
+        self.0.stderr(std::process::Stdio::piped());
+        self.0.stdin(std::process::Stdio::piped());
+        let mut child = self.0.spawn().stack_context(&log, "Failed to start child process")?;
+        let stdin = child.stdin.as_mut().unwrap();
+        stdin.write_all(data).stack_context(&log, "Error writing to child process stdin")?;
+        let output = child.wait_with_output().stack_context(&log, "Failed to wait for child process to exit")?;
+        if !output.status.success() {
+            return Err(
+                log.err_with(
+                    "Child process exited with error",
+                    ea!(code = output.status.code().dbg_str(), output = output.dbg_str()),
+                ),
+            );
+        }
+        return Ok(output.stdout);
+    }
+
     pub(crate) fn run_json_out<D: DeserializeOwned>(&mut self) -> Result<D, loga::Error> {
         let res = self.run_stdout()?;
         let log = Log::new().fork(ea!(command = self.0.dbg_str()));
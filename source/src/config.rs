@@ -1,11 +1,19 @@
 use {
+    loga::ea,
     schemars::JsonSchema,
-    serde::Deserialize,
+    serde::{
+        de::Error,
+        Deserialize,
+        Deserializer,
+    },
     std::path::PathBuf,
 };
 
 pub const OUTER_UUID: &'static str = "3d02cfd4-968a-4fe4-a2a0-fe84614485f6";
 pub const INNER_UUID: &'static str = "0afee777-4fca-45c6-9bed-64bf3091536b";
+pub const RAID_UUID: &'static str = "0ecf9627-7795-4798-b014-759800c1399e";
+pub const PARTITION_TYPE_UUID: &'static str = "ac5c9753-ce77-4cad-a81b-61b89e82d718";
+pub const PARTITION_UUID: &'static str = "ae30d571-7676-487d-a800-121508423890";
 
 #[derive(Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -41,9 +49,39 @@ pub enum PinMode {
     Text,
 }
 
+#[cfg(feature = "age")]
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum AgeIdentityMode {
+    /// Read the identity from a file - either an age identity file (X25519,
+    /// `AGE-SECRET-KEY-...`) or an OpenSSH private key (`ssh-ed25519` or `ssh-rsa`).
+    /// If the SSH key is passphrase protected, the passphrase is requested with
+    /// `systemd-ask-password`.
+    File(PathBuf),
+    /// Read the identity from stdin, in the same formats as `file`.
+    Stdin,
+    /// The key file was encrypted with a passphrase (`age --passphrase`) rather than
+    /// to recipients. The passphrase is requested with `systemd-ask-password`.
+    Passphrase,
+}
+
 #[derive(Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum PrivateImageKeyMode {
+    /// The key file is encrypted with `age` (to X25519 or SSH recipients, or with a
+    /// passphrase) instead of OpenPGP.
+    #[cfg(feature = "age")]
+    Age {
+        /// How to get the identity to decrypt the key file with.
+        identity: AgeIdentityMode,
+    },
+    /// A passphrase protected OpenPGP secret key file (ex: from `gpg
+    /// --export-secret-keys`) is used to decrypt the key file. The passphrase is
+    /// requested with `systemd-ask-password`.
+    SecretKey {
+        /// The armored or binary secret key file. Only its encryption subkeys are used.
+        secret_key_path: PathBuf,
+    },
     /// A GPG smartcard is used to decrypt a key file which is then used to
     /// initialize/unlock the volume. A prompt will be written to all system terminals.
     /// If your NFC reader has a light, the light will come on when it wants to unlock
@@ -70,6 +108,213 @@ pub struct IndirectKeyArgs {
     pub decrypt: Option<PathBuf>,
 }
 
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ThresholdKeyArgs {
+    /// The locations of the key shares, created with `volumesetup split-key`. Each
+    /// share should be encrypted to a different administrator's key.
+    ///
+    /// The number of shares required to unlock the volume is set when splitting the
+    /// key.
+    pub share_paths: Vec<PathBuf>,
+    /// How to unlock the shares. Only `smartcard` is supported: each share is
+    /// unlocked separately, with each administrator tapping their smartcard and
+    /// entering their PIN in turn. Other modes would use the same identity for every
+    /// share.
+    pub key_mode: PrivateImageKeyMode,
+    /// Additional data to decrypt, see `indirect_key`.
+    pub decrypt: Option<PathBuf>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Tpm2KeyArgs {
+    /// Directory to store the sealed key in (`key.pub`, `key.priv`). It must be on
+    /// storage that's available at boot, like the boot disk. A new random key is
+    /// sealed when the volume is initialized; the sealed key can only be unsealed by
+    /// the same TPM.
+    pub sealed_path: PathBuf,
+    /// PCRs the key is bound to - unsealing fails if any of them have changed since
+    /// the key was sealed. Defaults to `[7]` (secure boot state).
+    pub pcrs: Option<Vec<u8>>,
+    /// PCR bank, defaults to `sha256`.
+    pub pcr_bank: Option<String>,
+    /// The TCTI to pass to the `tpm2-tools` commands, ex: `swtpm:port=2321` to use a
+    /// software TPM. Defaults to the `tpm2-tools` default (`TPM2TOOLS_TCTI` or the
+    /// kernel resource manager).
+    pub tcti: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TangServer {
+    /// Ex: `http://tang.example.com`
+    pub url: String,
+    /// Path to the server's advertisement (the JSON from `<url>/adv`). The
+    /// advertisement is pinned rather than fetched, so a server impersonating the
+    /// Tang server can't get the volume key.
+    pub adv: PathBuf,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct TangKeyArgs {
+    /// Where to store the encrypted key (a Clevis JWE). It must be on storage that's
+    /// available at boot, like the boot disk. A new random key is encrypted when the
+    /// volume is initialized.
+    pub jwe_path: PathBuf,
+    /// The Tang servers to encrypt the key with. The key is split between them with
+    /// Shamir's secret sharing (Clevis `sss`) so that any `threshold` of them can
+    /// decrypt it.
+    pub servers: Vec<TangServer>,
+    /// How many servers must be reachable to unlock the volume. Defaults to 1.
+    pub threshold: Option<usize>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum EscrowRecipient {
+    /// An OpenPGP public key (cert) file.
+    Openpgp(PathBuf),
+    /// An age recipient (`age1...`) or SSH public key (`ssh-ed25519 ...`).
+    #[cfg(feature = "age")]
+    Age(String),
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RecoveryArgs {
+    /// Where to write the encrypted recovery key. This should be somewhere that
+    /// gets backed up, since the recovery key can't be recovered from the volume. With
+    /// `lvm` each logical volume gets its own key, with the logical volume name added
+    /// to the file name (ex: `recovery-home.asc`). Existing files are never
+    /// overwritten.
+    pub path: PathBuf,
+    /// Who to encrypt the recovery key to.
+    pub recipient: EscrowRecipient,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct HeaderBackupArgs {
+    /// Directory to write the encrypted header backups to, ex: on a second disk or
+    /// `/boot`. Backups are named after the volume UUID (or the volume group and
+    /// logical volume for `lvm`).
+    pub dir: PathBuf,
+    /// Who to encrypt the header backups to. The recipients must all be OpenPGP or
+    /// all be age.
+    pub recipients: Vec<EscrowRecipient>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Argon2idArgs {
+    /// Memory cost in KiB. Lower this on machines with little memory, since the
+    /// memory is needed to unlock the volume.
+    pub memory: Option<u32>,
+    /// Time cost (iterations). Skips benchmarking, can't be used with `iter_time`.
+    pub iterations: Option<u32>,
+    /// Benchmark to take this many milliseconds to unlock.
+    pub iter_time: Option<u32>,
+    /// Number of threads.
+    pub parallel: Option<u32>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Pbkdf2Args {
+    /// Number of iterations. Skips benchmarking, can't be used with `iter_time`.
+    pub iterations: Option<u32>,
+    /// Benchmark to take this many milliseconds to unlock.
+    pub iter_time: Option<u32>,
+}
+
+/// Key derivation function for keyslots.
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum LuksPbkdf {
+    /// Memory-hard, the LUKS2 default.
+    Argon2id(Argon2idArgs),
+    /// Not memory-hard, for machines that can't spare the memory for `argon2id` or
+    /// when required for compliance.
+    Pbkdf2(Pbkdf2Args),
+}
+
+/// Integrity protection (dm-integrity) for LUKS2.
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum LuksIntegrity {
+    /// HMAC-SHA256 tags alongside the normal cipher.
+    HmacSha256,
+    /// Authenticated encryption. Requires an AEAD `cipher`, `aes-gcm-random` if not
+    /// set.
+    Aead,
+}
+
+/// Parameters for `cryptsetup luksFormat`. Unset parameters use the `cryptsetup`
+/// defaults (see `cryptsetup --help`). These only affect new volumes, except
+/// `pbkdf` which is also used for keyslots added later (ex: with `rekey`).
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct LuksFormatArgs {
+    /// Cipher (ex: `aes-xts-plain64`).
+    pub cipher: Option<String>,
+    /// Key size in bits (ex: `512` for AES-256 with XTS).
+    pub key_size: Option<u32>,
+    /// Key derivation function and costs for keyslots.
+    pub pbkdf: Option<LuksPbkdf>,
+    /// Encryption sector size in bytes (ex: `4096`).
+    pub sector_size: Option<u32>,
+    /// LUKS2 label.
+    pub label: Option<String>,
+    /// LUKS2 subsystem.
+    pub subsystem: Option<String>,
+    /// Detect silent data corruption and tampering, which then cause read errors.
+    /// The device is wiped to initialize the integrity tags when the volume is
+    /// created, which can take hours for large disks.
+    pub integrity: Option<LuksIntegrity>,
+}
+
+/// How to find the disk holding the data of a volume with a detached header. The
+/// disk has no LUKS header, so it can't be found by UUID.
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum DataDisk {
+    /// The disk serial, as reported by `lsblk`.
+    Serial(String),
+    /// The disk's name in `/dev/disk/by-id` (ex:
+    /// `nvme-Samsung_SSD_980_1TB_S64DNF0R123456`).
+    ById(String),
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct DetachedHeaderArgs {
+    /// Where the LUKS header is kept: a file (ex: in the system image) or a separate
+    /// small block device. If it doesn't exist the volume is assumed to be new and
+    /// the header is created here, so when shipping it in a read-only image, copy it
+    /// in after the first boot. There's nothing on the data disk to recover the
+    /// volume with if the header is lost, see `luks_header_backup`.
+    pub header: PathBuf,
+    /// The disk to put the encrypted data on.
+    pub data_disk: DataDisk,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum UnlockMethod {
+    /// See `direct_key` encryption.
+    DirectKey(DirectKeyArgs),
+    /// See `indirect_key` encryption.
+    IndirectKey(IndirectKeyArgs),
+    /// See `threshold_key` encryption.
+    ThresholdKey(ThresholdKeyArgs),
+    /// See `tpm2_key` encryption.
+    Tpm2Key(Tpm2KeyArgs),
+    /// See `tang_key` encryption.
+    TangKey(TangKeyArgs),
+}
+
 #[derive(Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum EncryptionMode {
@@ -81,6 +326,130 @@ pub enum EncryptionMode {
     /// disk. This allows alternate keys to be used to unlock the disk, such as
     /// multiple administrators.
     IndirectKey(IndirectKeyArgs),
+    /// Like `indirect_key`, but the key is split into shares which are each
+    /// encrypted to a different administrator's key. Unlocking requires a number of
+    /// the shares to be decrypted, for two-person control.
+    ThresholdKey(ThresholdKeyArgs),
+    /// A random key is sealed to the local TPM2 and unsealed at boot with no
+    /// interaction, as long as the PCR values match. This protects the disk if
+    /// it's removed from the host, for unattended hosts.
+    Tpm2Key(Tpm2KeyArgs),
+    /// A random key is encrypted with Clevis using one or more Tang servers, and
+    /// decrypted at boot with no interaction as long as enough of the servers are
+    /// reachable. This way disks only unlock on the network the servers are on.
+    TangKey(TangKeyArgs),
+    /// Multiple ways to unlock the disk, each with its own LUKS keyslot. When
+    /// unlocking they're tried in order until one succeeds (ex: a key file, then a
+    /// smartcard, then a recovery password). All of them are used when initializing
+    /// the disk. Only supported with `ext4`, `xfs` and `lvm`.
+    AnyOf(Vec<UnlockMethod>),
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RaidLevel {
+    /// All disks mirror each other. Requires at least 2 disks.
+    Raid1,
+    /// Single parity. Requires at least 3 disks.
+    Raid5,
+    /// Double parity. Requires at least 4 disks.
+    Raid6,
+    /// Striped mirrors. Requires at least 4 disks.
+    Raid10,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RaidArgs {
+    /// RAID level of the array.
+    pub level: RaidLevel,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct PartitionArgs {
+    /// If there are no unused disks, create the partition in the largest free space
+    /// on a GPT disk that's already in use (ex: the boot disk).
+    pub use_free_space: Option<bool>,
+    /// The minimum free space (in bytes) to create a partition in when
+    /// `use_free_space` is set. Defaults to 1GiB.
+    pub min_size: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum BtrfsProfile {
+    /// One copy of everything, no redundancy.
+    Single,
+    /// Two copies on different disks. Requires at least 2 disks.
+    Raid1,
+    /// Two copies, striped. Requires at least 4 disks.
+    Raid10,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct BtrfsArgs {
+    /// Profile for data chunks. Defaults to `raid1`.
+    pub data_profile: Option<BtrfsProfile>,
+    /// Profile for metadata chunks. Defaults to `raid1`.
+    pub metadata_profile: Option<BtrfsProfile>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ZfsLayout {
+    /// All disks mirror each other. Requires at least 2 disks.
+    Mirror,
+    /// Single parity. Requires at least 3 disks.
+    Raidz1,
+    /// Double parity. Requires at least 4 disks.
+    Raidz2,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ZfsArgs {
+    /// Name of the pool. Defaults to `persistent`.
+    pub pool: Option<String>,
+    /// Layout of the vdev created from the unused disks. Defaults to `mirror`.
+    pub layout: Option<ZfsLayout>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum LvmFilesystem {
+    /// Format the logical volume ext4.
+    Ext4,
+    /// Format the logical volume xfs.
+    Xfs,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct LvmVolume {
+    /// Name of the logical volume.
+    pub name: String,
+    /// Size of the logical volume. This is passed to `lvcreate`, either as
+    /// `--extents` if it contains `%` (ex: `50%VG`, `100%FREE`) or otherwise `--size`
+    /// (ex: `100G`).
+    pub size: String,
+    /// Filesystem to format the logical volume with.
+    pub fs: LvmFilesystem,
+    /// The mount point of the logical volume.
+    pub mountpoint: PathBuf,
+    /// Ensure these directories (and parents) relative to the mountdir once it's
+    /// mounted.
+    pub ensure_dirs: Option<Vec<PathBuf>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct LvmArgs {
+    /// Name of the volume group. Defaults to `persistent`.
+    pub volume_group: Option<String>,
+    /// Logical volumes to create in the volume group, in order.
+    pub volumes: Vec<LvmVolume>,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -88,24 +457,272 @@ pub enum EncryptionMode {
 pub enum FilesystemMode {
     /// The largest unused disk will be used and formatted ext4.
     Ext4,
+    /// The largest unused disk will be used and formatted xfs.
+    Xfs,
     /// All unused disks will be added to the pool
     Bcachefs,
+    /// All unused disks will be added to a multi-device btrfs filesystem. Encryption
+    /// isn't supported in this mode.
+    Btrfs(BtrfsArgs),
+    /// All unused disks will be used to create a zpool. Faulted disks will be
+    /// replaced with new unused disks at boot. Encryption uses ZFS native encryption.
+    Zfs(ZfsArgs),
+    /// All unused disks will be added to an LVM volume group, and the volume group
+    /// will be split into multiple logical volumes with their own filesystems and
+    /// mountpoints. New disks will be added to the volume group at boot. Each logical
+    /// volume is encrypted separately.
+    ///
+    /// The top level `mountpoint` and `ensure_dirs` can't be used in this mode.
+    Lvm(LvmArgs),
 }
 
+/// Conditions on a disk. All set conditions must match. Globs use the `glob` crate
+/// syntax (`*`, `?`, `[...]`).
 #[derive(Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-pub struct Config {
-    #[serde(rename = "$schema", skip_serializing)]
-    pub _schema: Option<String>,
-    /// Override the default UUID.
+pub struct DiskMatch {
+    /// Glob matching the device path (ex: `/dev/nvme*`).
+    pub path: Option<String>,
+    /// Glob matching any of the device's names in `/dev/disk/by-id` (ex:
+    /// `nvme-Amazon_EC2_NVMe_Instance_Storage_*`).
+    pub by_id: Option<String>,
+    /// Glob matching the device model.
+    pub model: Option<String>,
+    /// Glob matching the device serial.
+    pub serial: Option<String>,
+    /// Glob matching the device WWN.
+    pub wwn: Option<String>,
+    /// Device transport, as reported by `lsblk` (ex: `nvme`, `sata`, `usb`).
+    pub transport: Option<String>,
+    /// Whether the device is rotational (hdd) or not (ssd, nvme).
+    pub rotational: Option<bool>,
+    /// Minimum size in bytes.
+    pub min_size: Option<u64>,
+    /// Maximum size in bytes.
+    pub max_size: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RemovablePolicy {
+    /// Never use USB disks.
+    Deny,
+    /// USB disks are treated like any other disk.
+    Allow,
+    /// Only use USB disks matching at least one of these (ex: by `by_id`).
+    AllowMatching(Vec<DiskMatch>),
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum WipePolicy {
+    /// Never use disks with existing filesystem, RAID, LVM, LUKS or partition table
+    /// signatures.
+    Deny,
+    /// Disks with existing signatures are overwritten like blank disks.
+    Allow,
+    /// Only overwrite disks with existing signatures if they match at least one of
+    /// these (ex: by `serial`).
+    AllowMatching(Vec<DiskMatch>),
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct DiskSelection {
+    /// Whether USB disks can be used. Defaults to `deny`. Other removable disks (ex:
+    /// SD cards) are treated like any other disk.
+    pub removable: Option<RemovablePolicy>,
+    /// Whether unmounted disks that already contain data (filesystems, RAID or LVM
+    /// members, LUKS volumes, partition tables) can be overwritten. Defaults to
+    /// `deny`.
+    pub allow_wipe: Option<WipePolicy>,
+    /// Only use disks matching at least one of these. If unset, all disks can be used.
+    pub include: Option<Vec<DiskMatch>>,
+    /// Never use disks matching any of these.
+    pub exclude: Option<Vec<DiskMatch>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Volume {
+    /// Override the default UUID. Must be unique when using multiple volumes, and the
+    /// first 8 characters must also be unique since they're used to name device
+    /// mapper devices and RAID arrays.
     pub uuid: Option<String>,
     /// How encryption should be handled.  Defaults to unencrypted.
     pub encryption: Option<EncryptionMode>,
+    /// Generate a recovery key when initializing an encrypted volume, and write it
+    /// encrypted to an escrow recipient. For `bcachefs` and `zfs`, which only support
+    /// a single passphrase, the primary passphrase is escrowed instead.
+    pub recovery: Option<RecoveryArgs>,
+    /// Back up the LUKS headers when they're created or a keyslot is changed. Only
+    /// used with LUKS encryption (`ext4`, `xfs`, `lvm`).
+    pub luks_header_backup: Option<HeaderBackupArgs>,
+    /// Keep the LUKS header off the data disk, so the disk looks like random data.
+    /// Only used with `ext4` and `xfs`, without `raid` or `partition`.
+    pub luks_detached_header: Option<DetachedHeaderArgs>,
+    /// LUKS format parameters. Only used with LUKS encryption (`ext4`, `xfs`, `lvm`).
+    pub luks_format: Option<LuksFormatArgs>,
     /// Filesystem to use, how to turn disks into filesystems.
     pub fs: Option<FilesystemMode>,
+    /// Assemble all unused disks into an md RAID array and put the volume on that
+    /// instead of only the largest disk. Only used with `ext4` and `xfs`.
+    pub raid: Option<RaidArgs>,
+    /// Restrict which unused disks can be used for the volume.
+    pub disks: Option<DiskSelection>,
+    /// Create a GPT partition with a well-known type and partition UUID and put the
+    /// volume on that instead of the whole disk. Only used with `ext4` and `xfs`, and
+    /// can't be combined with `raid`.
+    pub partition: Option<PartitionArgs>,
     /// The mount point of the volume.  Defaults to `/mnt/persistent`.
     pub mountpoint: Option<PathBuf>,
     /// Ensure these directories (and parents) relative to the mountdir once it's
     /// mounted.
     pub ensure_dirs: Option<Vec<PathBuf>>,
 }
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MultipleVolumes {
+    /// Set up multiple volumes, in order. Disks used by one volume won't be used by
+    /// later volumes.
+    pub volumes: Vec<Volume>,
+}
+
+#[derive(JsonSchema)]
+#[serde(untagged)]
+pub enum Volumes {
+    Multiple(MultipleVolumes),
+    /// A single volume configured with the top level options.
+    Single(Volume),
+}
+
+#[derive(JsonSchema)]
+pub struct Config {
+    #[serde(rename = "$schema", skip_serializing)]
+    pub _schema: Option<String>,
+    #[serde(flatten)]
+    pub volumes: Volumes,
+}
+
+// Picks the shape by the `volumes` key rather than trying each (untagged), so
+// mistakes get the specific field error instead of a generic one.
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields = serde_json::Map::deserialize(deserializer)?;
+        let schema = match fields.remove("$schema") {
+            Some(schema) => Some(serde_json::from_value(schema).map_err(D::Error::custom)?),
+            None => None,
+        };
+        let volumes = if fields.contains_key("volumes") {
+            Volumes::Multiple(
+                serde_json::from_value(serde_json::Value::Object(fields)).map_err(D::Error::custom)?,
+            )
+        } else {
+            Volumes::Single(serde_json::from_value(serde_json::Value::Object(fields)).map_err(D::Error::custom)?)
+        };
+        return Ok(Config {
+            _schema: schema,
+            volumes: volumes,
+        });
+    }
+}
+
+impl Config {
+    /// Returns `volumes`, or the single volume defined by the top level options.
+    pub fn into_volumes(self) -> Result<Vec<Volume>, loga::Error> {
+        let volumes = match self.volumes {
+            Volumes::Multiple(c) => c.volumes,
+            Volumes::Single(v) => vec![v],
+        };
+
+        // Volumes are identified by these, so they can't be shared
+        let mut seen = vec![];
+        for volume in &volumes {
+            let id = match volume.fs.as_ref().unwrap_or(&FilesystemMode::Bcachefs {}) {
+                FilesystemMode::Zfs(fs_args) => {
+                    format!("zfs pool {}", fs_args.pool.as_ref().map(|x| x.as_str()).unwrap_or("persistent"))
+                },
+                FilesystemMode::Lvm(fs_args) => {
+                    format!(
+                        "lvm volume group {}",
+                        fs_args.volume_group.as_ref().map(|x| x.as_str()).unwrap_or("persistent")
+                    )
+                },
+                _ => format!("uuid {}", volume.uuid.as_ref().map(|x| x.as_str()).unwrap_or(OUTER_UUID)),
+            };
+            if seen.contains(&id) {
+                return Err(loga::err_with("Multiple volumes are configured with the same identity", ea!(id = id)));
+            }
+            seen.push(id);
+        }
+
+        // Volumes would be mounted over each other
+        let mut seen = vec![];
+        for volume in &volumes {
+            let mountpoints = match &volume.fs {
+                Some(FilesystemMode::Lvm(fs_args)) => fs_args.volumes.iter().map(|lv| lv.mountpoint.clone()).collect(),
+                _ => vec![volume.mountpoint.clone().unwrap_or_else(|| PathBuf::from("/mnt/persistent"))],
+            };
+            for mountpoint in mountpoints {
+                if seen.contains(&mountpoint) {
+                    return Err(
+                        loga::err_with(
+                            "Multiple volumes are configured with the same mountpoint",
+                            ea!(mountpoint = mountpoint.to_string_lossy()),
+                        ),
+                    );
+                }
+                seen.push(mountpoint);
+            }
+        }
+
+        // Device mapper and md names use a shortened UUID
+        let mut seen = vec![];
+        for volume in &volumes {
+            let Some(uuid) = &volume.uuid else {
+                continue;
+            };
+            if uuid == OUTER_UUID {
+                continue;
+            }
+            let short = uuid.chars().take(8).collect::<String>().to_lowercase();
+            if seen.contains(&short) {
+                return Err(
+                    loga::err_with(
+                        "Multiple volumes have UUIDs starting with the same 8 characters, which are used for device names",
+                        ea!(prefix = short),
+                    ),
+                );
+            }
+            seen.push(short);
+        }
+
+        // Shares must be unlocked by different administrators
+        for volume in &volumes {
+            let thresholds = match &volume.encryption {
+                Some(EncryptionMode::ThresholdKey(args)) => vec![args],
+                Some(EncryptionMode::AnyOf(methods)) => {
+                    let mut out = vec![];
+                    for method in methods {
+                        if let UnlockMethod::ThresholdKey(args) = method {
+                            out.push(args);
+                        }
+                    }
+                    out
+                },
+                _ => vec![],
+            };
+            for args in thresholds {
+                match &args.key_mode {
+                    #[cfg(feature = "smartcard")]
+                    PrivateImageKeyMode::Smartcard { .. } => { },
+                    _ => {
+                        return Err(loga::err("`threshold_key` shares can only be unlocked with `smartcard`"));
+                    },
+                }
+            }
+        }
+        return Ok(volumes);
+    }
+}